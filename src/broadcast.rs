@@ -1,29 +1,203 @@
-use std::sync::Arc;
+use std::sync::{Arc, Mutex as SMutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicUsize, Ordering};
 use std::pin::Pin;
 use std::cmp;
+use std::fmt;
 use std::io::Read;
+use std::collections::{HashMap, VecDeque};
 use hyper::http::Request;
 use hyper::{StatusCode, HeaderMap};
 use hyper::header::HeaderValue;
+use bytes::Bytes;
 use bytes::buf::Buf;
+use serde::Deserialize;
 use serde_json::{json, Value as JsonValue};
+use chrono::prelude::*;
 use futures::future::Future;
-use futures::task::{Poll, Context};
+use futures::stream::{FuturesUnordered, StreamExt};
+use futures::task::{AtomicWaker, Poll, Context};
 use tokio::time as ttime;
 use crate::BoxedError;
 use crate::db;
+use crate::ratelimit::RateLimiter;
 
 type HyperClient = hyper::Client<hyper_tls::HttpsConnector<hyper::client::HttpConnector>>;
 
-#[derive(Debug, Clone)]
+// Threads and forum channels need v9 or later.
+pub const DEFAULT_API_URL: &str = "https://discord.com/api/v9/";
+
+// Everything needed to make a request to Discord's API. The base URL can point at a mock server instead.
+// Rate limits belong to the token, so every broadcast shares them through here.
+pub struct DiscordApi {
+    client: Arc<HyperClient>,
+    bot_token: String,
+    base_url: String,
+    limiter: SMutex<RateLimiter>,
+    // Set once Discord rejects the token. Nothing will work again until it's replaced and the bot restarted.
+    unauthorized: AtomicBool,
+}
+
+impl DiscordApi {
+    pub fn new(client: Arc<HyperClient>, bot_token: String, base_url: &str) -> Self {
+        let mut base_url = base_url.to_owned();
+        if !base_url.ends_with('/') {
+            base_url.push('/');
+        }
+
+        Self {
+            client,
+            bot_token,
+            base_url,
+            limiter: SMutex::new(RateLimiter::new()),
+            unauthorized: AtomicBool::new(false),
+        }
+    }
+
+    fn limiter(&self) -> MutexGuard<'_, RateLimiter> {
+        self.limiter.lock().unwrap()
+    }
+
+    fn is_unauthorized(&self) -> bool {
+        self.unauthorized.load(Ordering::Relaxed)
+    }
+
+    // Fetched directly, serenity doesn't know about thread or forum channels.
+    pub async fn get_channel(&self, channel_id: u64) -> Result<ChannelInfo, BoxedError> {
+        let request = Request::builder()
+            .uri(format!("{}channels/{}", self.base_url, channel_id))
+            .method("GET")
+            .header("Authorization", "Bot ".to_owned() + &self.bot_token)
+            .header("User-Agent", "JohnWickBot(https://wickshopbot.com, 0.1)")
+            .body(hyper::Body::empty())?;
+        let res = ttime::timeout(REQUEST_TIMEOUT, self.client.request(request)).await??;
+        if !res.status().is_success() {
+            return Err(format!("Could not fetch channel {}: {}", channel_id, res.status()).into());
+        }
+        let data = ttime::timeout(REQUEST_TIMEOUT, hyper::body::to_bytes(res.into_body())).await??;
+
+        Ok(serde_json::from_slice(&data)?)
+    }
+
+    // How long a broadcast to these channels would take at the current rate limits. Nothing goes out
+    // until a global pause is over, and then only 50 requests a second. Announcement channels take
+    // two requests, webhooks don't count against the bot's limit at all.
+    pub fn estimate_duration(&self, channels: &[db::Channel]) -> ttime::Duration {
+        let limiter = self.limiter();
+        let now = ttime::Instant::now();
+        let paused = match limiter.global_reset() {
+            Some(reset) => reset.saturating_duration_since(now),
+            None => ttime::Duration::from_secs(0),
+        };
+        let mut requests = 0;
+        // A channel whose bucket is empty can't go until it resets, however quick everything else is.
+        let mut bucket_wait = ttime::Duration::from_secs(0);
+        for channel in channels {
+            if let Some(wait) = limiter.wait_time(BroadcastAction::Send.route(channel.kind), channel.discord) {
+                bucket_wait = cmp::max(bucket_wait, wait);
+            }
+            requests += match channel.kind {
+                db::CHANNEL_WEBHOOK => 0,
+                db::CHANNEL_NEWS | db::CHANNEL_UNKNOWN => 2,
+                _ => 1,
+            };
+        }
+
+        let by_global_limit = ttime::Duration::from_millis(requests * 1000 / GLOBAL_REQUESTS_PER_SECOND);
+        cmp::max(paused + by_global_limit, bucket_wait)
+    }
+
+    fn webhook_url(&self, id: i64, token: &str) -> String {
+        format!("{}webhooks/{}/{}", self.base_url, id, token)
+    }
+
+    // Only Discord webhook URLs are accepted, and only the ID and token are kept from them, so nothing else gets requested.
+    pub async fn get_webhook(&self, url: &str) -> Result<WebhookInfo, BoxedError> {
+        let (id, token) = match parse_webhook_url(url) {
+            Some(w) => w,
+            None => return Err("Not a Discord webhook URL".into()),
+        };
+        let url = self.webhook_url(id as i64, token);
+
+        let request = Request::builder()
+            .uri(url.as_str())
+            .method("GET")
+            .header("User-Agent", "JohnWickBot(https://wickshopbot.com, 0.1)")
+            .body(hyper::Body::empty())?;
+        let res = ttime::timeout(REQUEST_TIMEOUT, self.client.request(request)).await??;
+        if !res.status().is_success() {
+            return Err(format!("Could not fetch webhook {}: {}", id, res.status()).into());
+        }
+        let data = ttime::timeout(REQUEST_TIMEOUT, hyper::body::to_bytes(res.into_body())).await??;
+        let webhook: JsonValue = serde_json::from_slice(&data)?;
+
+        Ok(WebhookInfo {
+            id,
+            guild_id: webhook["guild_id"].as_str().map(|g| g.to_owned()),
+            token: token.to_owned(),
+        })
+    }
+}
+
+pub struct WebhookInfo {
+    pub id: u64,
+    pub guild_id: Option<String>,
+    pub token: String,
+}
+
+const WEBHOOK_HOSTS: &[&str] = &["discord.com", "discordapp.com", "canary.discord.com", "ptb.discord.com", "canary.discordapp.com", "ptb.discordapp.com"];
+
+// Pulls the ID and token out of https://discord.com/api[/v<n>]/webhooks/<id>/<token>, on any of Discord's hosts.
+fn parse_webhook_url(url: &str) -> Option<(u64, &str)> {
+    let url = url.split(&['?', '#'][..]).next()?;
+    let rest = url.strip_prefix("https://")?;
+    let mut parts = rest.split('/');
+    if !WEBHOOK_HOSTS.contains(&parts.next()?) || parts.next()? != "api" {
+        return None;
+    }
+    let mut next = parts.next()?;
+    if next.starts_with('v') && next[1..].parse::<u32>().is_ok() {
+        next = parts.next()?;
+    }
+    if next != "webhooks" {
+        return None;
+    }
+    let id = parts.next()?.parse::<u64>().ok()?;
+    let token = parts.next()?;
+    let valid_token = !token.is_empty() && token.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    // Nothing may follow the token but a trailing slash.
+    match (valid_token, parts.next(), parts.next()) {
+        (true, None, _) | (true, Some(""), None) => Some((id, token)),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChannelInfo {
+    #[serde(rename = "type")]
+    pub kind: i16,
+    // Snowflakes come back as strings
+    #[serde(default)]
+    pub guild_id: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 enum BroadcastResultType {
     Success,
+    BadRequest,
+    Unauthorized,
+    InvalidFormBody,
+    ThreadArchived,
     Forbidden,
     NotFound,
     UnknownChannel,
+    UnknownWebhook,
     MissingPermissions,
     MissingAccess,
+    CannotMessageUser,
     RateLimited,
+    GlobalRateLimited,
+    CloudflareBan,
+    ServerError,
     Unknown,
 }
 
@@ -33,27 +207,43 @@ struct BroadcastResultInner {
     rate_limit_stamp: u64,
     rate_limit_left: u32,
     rate_limit_retry: u64,
+    rate_limit_reset_after: u64,
     rate_limit_bucket: Option<String>,
+    message_id: Option<i64>,
+    // The channel's type, when it had to be looked up for the request.
+    channel_kind: Option<i16>,
+    // Set when a DM channel was opened.
+    dm_channel: Option<i64>,
 }
 
 type BroadcastResult = Result<BroadcastResultInner, BoxedError>;
 
-fn parse_header(data: Option<&HeaderValue>) -> Result<u64, BoxedError> {
+// Cloudflare bans don't say how long they last, Discord's docs put them at an hour or so.
+const CLOUDFLARE_BAN_WAIT: u64 = 60 * 60 * 1000;
+
+fn parse_header(data: Option<&HeaderValue>) -> Result<f64, BoxedError> {
     match data {
-        Some(d) => {
-            let f: f64 = d.to_str()?.parse()?;
-            Ok(f as u64)
-        },
-        None => Ok(0),
+        Some(d) => Ok(d.to_str()?.parse()?),
+        None => Ok(0.0),
+    }
+}
+
+fn is_global_limit(headers: &HeaderMap) -> bool {
+    if headers.contains_key("X-RateLimit-Global") {
+        return true;
+    }
+    match headers.get("X-RateLimit-Scope") {
+        Some(scope) => scope == "global",
+        None => false,
     }
 }
 
-fn parse_header_wrap(data: Option<&HeaderValue>) -> u64 {
+fn parse_header_wrap(data: Option<&HeaderValue>) -> f64 {
     match parse_header(data) {
         Ok(d) => d,
         Err(e) => {
             println!("Error: {:#?}", e);
-            0
+            0.0
         }
     }
 }
@@ -62,48 +252,111 @@ impl BroadcastResultInner {
     fn new(status_code: StatusCode, headers: &HeaderMap) -> Self {
         Self {
             status: match status_code {
-                StatusCode::OK => BroadcastResultType::Success,
+                StatusCode::OK | StatusCode::NO_CONTENT => BroadcastResultType::Success,
+                StatusCode::BAD_REQUEST => BroadcastResultType::BadRequest,
+                StatusCode::UNAUTHORIZED => BroadcastResultType::Unauthorized,
                 StatusCode::FORBIDDEN => BroadcastResultType::Forbidden,
-                StatusCode::TOO_MANY_REQUESTS => BroadcastResultType::RateLimited,
+                StatusCode::TOO_MANY_REQUESTS => match is_global_limit(headers) {
+                    true => BroadcastResultType::GlobalRateLimited,
+                    false => BroadcastResultType::RateLimited,
+                },
                 StatusCode::NOT_FOUND => BroadcastResultType::NotFound,
+                s if s.is_server_error() => BroadcastResultType::ServerError,
                 _ => BroadcastResultType::Unknown,
             },
             rate_limit_left: parse_header_wrap(headers.get("X-RateLimit-Remaining")) as u32,
-            rate_limit_stamp: parse_header_wrap(headers.get("X-RateLimit-Reset")),
-            rate_limit_retry: 400,
-            rate_limit_bucket: match headers.get("X-RateLimit-Bucket") {
-                Some(val) => Some(val.to_str().unwrap().to_owned()),
-                None => None,
+            rate_limit_stamp: parse_header_wrap(headers.get("X-RateLimit-Reset")) as u64,
+            rate_limit_retry: (parse_header_wrap(headers.get("Retry-After")) * 1000.0) as u64,
+            rate_limit_reset_after: (parse_header_wrap(headers.get("X-RateLimit-Reset-After")) * 1000.0) as u64,
+            rate_limit_bucket: match headers.get("X-RateLimit-Bucket").map(|v| v.to_str()) {
+                Some(Ok(val)) => Some(val.to_owned()),
+                _ => None,
             },
+            message_id: None,
+            channel_kind: None,
+            dm_channel: None,
+        }
+    }
+
+    fn dry_run() -> Self {
+        Self {
+            status: BroadcastResultType::Success,
+            rate_limit_stamp: 0,
+            rate_limit_left: 1,
+            rate_limit_retry: 0,
+            rate_limit_reset_after: 0,
+            rate_limit_bucket: None,
+            message_id: None,
+            channel_kind: None,
+            dm_channel: None,
         }
     }
 
     fn from(&self, message_body: String) -> Self {
+        if let BroadcastResultType::Success = self.status {
+            if message_body.is_empty() {
+                // Deletes don't return anything.
+                return self.clone();
+            }
+        }
+
         let response: JsonValue = match serde_json::from_str(&message_body) {
             Ok(val) => val,
             Err(e) => {
-                println!("JSON Error: {} {}", &message_body, e);
-                return self.clone();
+                let mut res = self.clone();
+                match self.status {
+                    BroadcastResultType::RateLimited | BroadcastResultType::GlobalRateLimited => {
+                        // A 429 without Discord's JSON body comes from Cloudflare, every request is banned.
+                        println!("Cloudflare Ban: {}", &message_body);
+                        res.status = BroadcastResultType::CloudflareBan;
+                        if res.rate_limit_retry == 0 {
+                            res.rate_limit_retry = CLOUDFLARE_BAN_WAIT;
+                        }
+                    },
+                    _ => println!("JSON Error: {} {}", &message_body, e),
+                };
+                return res;
             },
         };
         let mut res = self.clone();
         match self.status {
+            BroadcastResultType::Success => {
+                // Snowflakes come back as strings
+                res.message_id = response["id"].as_str().and_then(|id| id.parse().ok());
+            },
+            BroadcastResultType::BadRequest => {
+                if let Some(code) = response["code"].as_u64() {
+                    res.status = match code {
+                        40001 => BroadcastResultType::Unauthorized,
+                        50035 => BroadcastResultType::InvalidFormBody,
+                        50083 => BroadcastResultType::ThreadArchived,
+                        _ => BroadcastResultType::BadRequest,
+                    };
+                }
+            },
             BroadcastResultType::Forbidden => {
-                let code = response["code"].as_u64().unwrap();
-                res.status = match code {
-                    50001 => BroadcastResultType::MissingAccess,
-                    50013 => BroadcastResultType::MissingPermissions,
-                    _ => BroadcastResultType::Unknown,
-                };
+                if let Some(code) = response["code"].as_u64() {
+                    res.status = match code {
+                        50001 => BroadcastResultType::MissingAccess,
+                        50013 => BroadcastResultType::MissingPermissions,
+                        50007 => BroadcastResultType::CannotMessageUser,
+                        _ => BroadcastResultType::Unknown,
+                    };
+                }
             },
-            BroadcastResultType::RateLimited => {
-                let limit = response["retry_after"].as_f64().unwrap();
-                res.rate_limit_retry = (limit * 1000.0) as u64;
+            BroadcastResultType::RateLimited | BroadcastResultType::GlobalRateLimited => {
+                if let Some(limit) = response["retry_after"].as_f64() {
+                    res.rate_limit_retry = (limit * 1000.0) as u64;
+                }
+                if let Some(true) = response["global"].as_bool() {
+                    res.status = BroadcastResultType::GlobalRateLimited;
+                }
             },
             BroadcastResultType::NotFound => {
                 if let Some(code) = response["code"].as_u64() {
                     res.status = match code {
                         10003 => BroadcastResultType::UnknownChannel,
+                        10015 => BroadcastResultType::UnknownWebhook,
                         _ => BroadcastResultType::Unknown,
                     };
                 }
@@ -115,181 +368,1844 @@ impl BroadcastResultInner {
     }
 }
 
-async fn send_message(client: Arc<HyperClient>, bot_token: String, message_content: String, channel_id: i64) -> BroadcastResult {
-    let uri = "https://discord.com/api/v8/channels/".to_owned() + &channel_id.to_string() + "/messages";
-    let request_body = json!({
-        "content": message_content
-    }).to_string();
-    let request = Request::builder()
+const SHOP_URL: &str = "https://wickshopbot.com/";
+const WEBSITE_URL: &str = "https://johnwickbot.shop/";
+const SHOP_COLOUR: u32 = 0x5865F2;
+
+// Builds the request body for a broadcast, stored with the job so it can be resumed.
+pub fn message_payload(content: &str) -> String {
+    json!({
+        "content": content
+    }).to_string()
+}
+
+// Replaces the content of a broadcast message and drops its embed.
+pub fn edit_payload(content: &str) -> String {
+    json!({
+        "content": content,
+        "embed": null,
+    }).to_string()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BroadcastAction {
+    Send,
+    Edit,
+    Delete,
+    // Publishes a message in an announcement channel so following servers get it.
+    Crosspost,
+    // Reopens an archived thread so the message can be sent again.
+    Unarchive,
+    // DMs go through a channel of their own, which has to be opened first. Opening one that exists just returns it.
+    OpenDm,
+    // Finds out what kind of channel an older subscription is, so the message can be sent the right way.
+    Lookup,
+}
+
+impl BroadcastAction {
+    fn route(&self, kind: i16) -> &'static str {
+        match (self, kind) {
+            (BroadcastAction::Send, db::CHANNEL_WEBHOOK) => "POST /webhooks/{webhook_id}/{webhook_token}",
+            (BroadcastAction::Edit, db::CHANNEL_WEBHOOK) => "PATCH /webhooks/{webhook_id}/{webhook_token}/messages/{message_id}",
+            (BroadcastAction::Delete, db::CHANNEL_WEBHOOK) => "DELETE /webhooks/{webhook_id}/{webhook_token}/messages/{message_id}",
+            (BroadcastAction::Send, db::CHANNEL_FORUM) => "POST /channels/{channel_id}/threads",
+            (BroadcastAction::Send, _) => "POST /channels/{channel_id}/messages",
+            (BroadcastAction::Edit, _) => "PATCH /channels/{channel_id}/messages/{message_id}",
+            (BroadcastAction::Delete, db::CHANNEL_FORUM) => "DELETE /channels/{channel_id}",
+            (BroadcastAction::Delete, _) => "DELETE /channels/{channel_id}/messages/{message_id}",
+            (BroadcastAction::Crosspost, _) => "POST /channels/{channel_id}/messages/{message_id}/crosspost",
+            (BroadcastAction::Unarchive, _) => "PATCH /channels/{channel_id}",
+            (BroadcastAction::OpenDm, _) => "POST /users/@me/channels",
+            (BroadcastAction::Lookup, _) => "GET /channels/{channel_id}",
+        }
+    }
+
+    // Whether the result decides if the channel got the broadcast.
+    fn delivers(&self) -> bool {
+        matches!(self, BroadcastAction::Send | BroadcastAction::Unarchive | BroadcastAction::OpenDm | BroadcastAction::Lookup)
+    }
+}
+
+// Forum channels get a new post for every broadcast, named after the embed title or the message.
+fn thread_payload(payload: &str) -> String {
+    let mut message: JsonValue = match serde_json::from_str(payload) {
+        Ok(v) => v,
+        Err(_) => return String::new(),
+    };
+    let name = match (message["embed"]["title"].as_str(), message["content"].as_str()) {
+        (Some(title), _) => title.to_owned(),
+        (None, Some(content)) if !content.is_empty() => content.chars().take(THREAD_NAME_MAX).collect(),
+        _ => "John Wick Bot".to_owned(),
+    };
+    with_embeds(&mut message);
+
+    json!({
+        "name": name,
+        "message": message,
+    }).to_string()
+}
+
+const THREAD_NAME_MAX: usize = 100;
+const WEBHOOK_USERNAME: &str = "John Wick Bot";
+
+// Webhooks post under whatever name they're given rather than the bot's, the avatar is left as the webhook's own.
+// Edits can't change the name, so it's only set on new messages.
+fn webhook_payload(payload: &str, identity: bool) -> String {
+    let mut message: JsonValue = match serde_json::from_str(payload) {
+        Ok(v) => v,
+        Err(_) => return String::new(),
+    };
+    with_embeds(&mut message);
+    if identity {
+        message["username"] = json!(WEBHOOK_USERNAME);
+    }
+
+    message.to_string()
+}
+
+// Pings the channel's role ahead of the message. Nothing else in the payload is allowed to ping.
+fn with_mention(payload: &str, role: i64, forum: bool) -> String {
+    let mut payload: JsonValue = match serde_json::from_str(payload) {
+        Ok(v) => v,
+        Err(_) => return String::new(),
+    };
+    let message = match forum {
+        true => &mut payload["message"],
+        false => &mut payload,
+    };
+    let content = match message["content"].as_str() {
+        Some(c) if !c.is_empty() => format!("<@&{}> {}", role, c),
+        _ => format!("<@&{}>", role),
+    };
+    message["content"] = json!(content);
+    message["allowed_mentions"] = json!({
+        "parse": [],
+        "roles": [role.to_string()],
+    });
+
+    payload.to_string()
+}
+
+// Threads and webhooks only take a list of embeds.
+fn with_embeds(message: &mut JsonValue) {
+    match message.as_object_mut().and_then(|m| m.remove("embed")) {
+        Some(JsonValue::Null) => message["embeds"] = json!([]),
+        Some(embed) => message["embeds"] = json!([embed]),
+        None => (),
+    };
+}
+
+// What every channel in a broadcast gets sent.
+struct BroadcastContent {
+    payload: String,
+    // The payload wrapped up as a new forum post.
+    thread_payload: String,
+    webhook_payload: String,
+    attachment: Option<Attachment>,
+    // Only this channel is actually sent to, everyone else gets a pretend success.
+    dry_run: Option<i64>,
+}
+
+// The JW server sends either the image filename, or an object with the filename and any embed fields it wants to set.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ShopImage {
+    image: String,
+    #[serde(default)]
+    title: Option<String>,
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(default)]
+    colour: Option<u32>,
+    #[serde(default)]
+    footer: Option<String>,
+    // Upload the image with the message rather than linking it, for guilds that turn off link embeds.
+    #[serde(default)]
+    attach: bool,
+    // Set by the server when it wants something other than the image name to tell broadcasts apart.
+    #[serde(default)]
+    id: Option<String>,
+    // What's in today's shop, for channels that only want certain items.
+    #[serde(default)]
+    pub items: Vec<ShopItem>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ShopItem {
+    pub name: String,
+    #[serde(default)]
+    pub rarity: Option<String>,
+    #[serde(default)]
+    pub set: Option<String>,
+}
+
+impl ShopItem {
+    fn matches(&self, filter: &db::ChannelFilter) -> bool {
+        let value = match filter.kind.as_str() {
+            db::FILTER_RARITY => &self.rarity,
+            db::FILTER_SET => &self.set,
+            _ => return false,
+        };
+        match value {
+            Some(v) => v.eq_ignore_ascii_case(&filter.value),
+            None => false,
+        }
+    }
+}
+
+// A channel with filters only gets the shop when at least one item matches one of them.
+pub fn shop_matches(items: &[ShopItem], filters: &[db::ChannelFilter]) -> bool {
+    filters.is_empty() || items.iter().any(|i| filters.iter().any(|f| i.matches(f)))
+}
+
+impl ShopImage {
+    pub fn from_value(value: &JsonValue) -> Option<Self> {
+        match value {
+            JsonValue::String(image) => Some(Self {
+                image: image.to_owned(),
+                ..Default::default()
+            }),
+            JsonValue::Object(_) => match serde_json::from_value(value.clone()) {
+                Ok(shop) => Some(shop),
+                Err(e) => {
+                    println!("Invalid Shop Image: {}", e);
+                    None
+                },
+            },
+            _ => None,
+        }
+    }
+
+    pub fn image_url(&self) -> String {
+        if self.image.starts_with("https://") {
+            return self.image.clone();
+        }
+        SHOP_URL.to_owned() + &self.image
+    }
+
+    // The same shop shouldn't go out twice, even if the server sends it again after a reconnect.
+    pub fn idempotency_key(&self) -> String {
+        match &self.id {
+            Some(id) => format!("image:{}", id),
+            None => format!("image:{}", self.image),
+        }
+    }
+
+    pub fn attachment_url(&self) -> Option<String> {
+        match self.attach {
+            true => Some(self.image_url()),
+            false => None,
+        }
+    }
+
+    pub fn payload(&self) -> String {
+        let utc: DateTime<Utc> = Utc::now();
+        let title = match &self.title {
+            Some(t) => t.clone(),
+            None => format!("Item Shop - {}", utc.format("%B %-d, %Y")),
+        };
+        let mut embed = json!({
+            "title": title,
+            "url": WEBSITE_URL,
+            "color": self.colour.unwrap_or(SHOP_COLOUR),
+            "image": {
+                "url": match self.attach {
+                    true => "attachment://".to_owned() + &file_name(&self.image),
+                    false => self.image_url(),
+                },
+            },
+            "footer": {
+                "text": match &self.footer {
+                    Some(f) => f.as_str(),
+                    None => "johnwickbot.shop",
+                },
+            },
+            "timestamp": utc.to_rfc3339(),
+        });
+        if let Some(description) = &self.description {
+            embed["description"] = JsonValue::String(description.clone());
+        }
+
+        json!({
+            "embed": embed
+        }).to_string()
+    }
+}
+
+fn file_name(url: &str) -> String {
+    match url.rsplit('/').next() {
+        Some(name) if !name.is_empty() => name.to_owned(),
+        _ => "shop.png".to_owned(),
+    }
+}
+
+// A file uploaded alongside every message in a broadcast. It's fetched once, the data is shared between requests.
+#[derive(Clone)]
+pub struct Attachment {
+    filename: String,
+    data: Bytes,
+}
+
+pub async fn fetch_attachment(client: &Arc<HyperClient>, url: &str) -> Result<Attachment, BoxedError> {
+    let res = ttime::timeout(REQUEST_TIMEOUT, client.get(url.parse()?)).await??;
+    if res.status() != StatusCode::OK {
+        return Err(format!("Could not fetch attachment {}: {}", url, res.status()).into());
+    }
+    let data = ttime::timeout(REQUEST_TIMEOUT, hyper::body::to_bytes(res.into_body())).await??;
+
+    Ok(Attachment {
+        filename: file_name(url),
+        data,
+    })
+}
+
+const MULTIPART_BOUNDARY: &str = "JohnWickBotAttachmentBoundary4c1f93";
+
+fn multipart_body(payload: String, attachment: &Attachment) -> (hyper::Body, usize) {
+    let content_type = match attachment.filename.ends_with(".png") {
+        true => "image/png",
+        false => "application/octet-stream",
+    };
+    let head = format!(
+        "--{b}\r\nContent-Disposition: form-data; name=\"payload_json\"\r\nContent-Type: application/json\r\n\r\n{p}\r\n--{b}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{f}\"\r\nContent-Type: {t}\r\n\r\n",
+        b = MULTIPART_BOUNDARY, p = payload, f = attachment.filename, t = content_type,
+    );
+    let tail = format!("\r\n--{}--\r\n", MULTIPART_BOUNDARY);
+    let length = head.len() + attachment.data.len() + tail.len();
+
+    // Chunks keep the image data shared instead of copying it into every request body.
+    let chunks: Vec<Result<Bytes, std::io::Error>> = vec![Ok(Bytes::from(head)), Ok(attachment.data.clone()), Ok(Bytes::from(tail))];
+    (hyper::Body::wrap_stream(futures::stream::iter(chunks)), length)
+}
+
+async fn send_message(api: Arc<DiscordApi>, content: Arc<BroadcastContent>, request: &BroadcastInstance) -> BroadcastResult {
+    let action = request.action;
+    let target = &request.channel;
+    let message_id = request.message_id;
+    if let Some(test_channel) = content.dry_run {
+        if test_channel != target.discord {
+            return Ok(BroadcastResultInner::dry_run());
+        }
+    }
+    let channel_id = request.dm_channel.unwrap_or(target.discord);
+    let forum = target.kind == db::CHANNEL_FORUM;
+    let channel = api.base_url.clone() + "channels/" + &channel_id.to_string();
+    let webhook = target.webhook.as_ref().map(|token| api.webhook_url(target.discord, token));
+    let uri = match (&webhook, action, message_id) {
+        (_, BroadcastAction::OpenDm, _) => api.base_url.clone() + "users/@me/channels",
+        // Waits for the message to be created, so its ID comes back for retracting later.
+        (Some(url), BroadcastAction::Send, _) => url.clone() + "?wait=true",
+        (Some(url), _, Some(id)) => url.clone() + "/messages/" + &id.to_string(),
+        (_, BroadcastAction::Send, _) if forum => channel + "/threads",
+        (_, BroadcastAction::Unarchive, _) | (_, BroadcastAction::Lookup, _) => channel,
+        // A forum post is a thread, and its first message shares the thread's ID.
+        (_, BroadcastAction::Delete, Some(id)) if forum => api.base_url.clone() + "channels/" + &id.to_string(),
+        (_, BroadcastAction::Edit, Some(id)) if forum => api.base_url.clone() + "channels/" + &id.to_string() + "/messages/" + &id.to_string(),
+        (_, BroadcastAction::Crosspost, Some(id)) => channel + "/messages/" + &id.to_string() + "/crosspost",
+        (_, _, Some(id)) => channel + "/messages/" + &id.to_string(),
+        (_, _, None) => channel + "/messages",
+    };
+    let payload = match (&request.payload, &target.webhook, action) {
+        (Some(p), _, _) => p.clone(),
+        (None, Some(_), _) => content.webhook_payload.clone(),
+        (None, None, BroadcastAction::Send) if forum => content.thread_payload.clone(),
+        _ => content.payload.clone(),
+    };
+    // Only new messages ping, edits keep the mention so the message reads the same.
+    let payload = match (target.role, action) {
+        (Some(role), BroadcastAction::Send) => with_mention(&payload, role, forum && target.webhook.is_none()),
+        (Some(role), BroadcastAction::Edit) if !payload.is_empty() => with_mention(&payload, role, false),
+        _ => payload,
+    };
+    let mut builder = Request::builder()
         .uri(uri)
-        .method("POST")
-        .header("Authorization", "Bot ".to_owned() + &bot_token)
-        .header("Content-Type", "application/json")
-        .header("User-Agent", "JohnWickBot(https://wickshopbot.com, 0.1)")
-        .body(hyper::Body::from(request_body))
-        .unwrap();
-
-    let req = client.request(request).await.unwrap();
+        .method(match action {
+            BroadcastAction::Send | BroadcastAction::Crosspost | BroadcastAction::OpenDm => "POST",
+            BroadcastAction::Edit | BroadcastAction::Unarchive => "PATCH",
+            BroadcastAction::Lookup => "GET",
+            BroadcastAction::Delete => "DELETE",
+        })
+        .header("User-Agent", "JohnWickBot(https://wickshopbot.com, 0.1)");
+    // The webhook URL carries its own token, the bot's must never be sent along with it.
+    if target.webhook.is_none() {
+        builder = builder.header("Authorization", "Bot ".to_owned() + &api.bot_token);
+    }
+    let request = match (action, &content.attachment) {
+        (BroadcastAction::Delete, _) | (BroadcastAction::Crosspost, _) | (BroadcastAction::Lookup, _) => builder.body(hyper::Body::empty()),
+        (BroadcastAction::Unarchive, _) => {
+            builder
+                .header("Content-Type", "application/json")
+                .body(hyper::Body::from(json!({ "archived": false }).to_string()))
+        },
+        (BroadcastAction::OpenDm, _) => {
+            builder
+                .header("Content-Type", "application/json")
+                .body(hyper::Body::from(json!({ "recipient_id": target.discord.to_string() }).to_string()))
+        },
+        (BroadcastAction::Send, Some(file)) => {
+            let (body, length) = multipart_body(payload, file);
+            builder
+                .header("Content-Type", "multipart/form-data; boundary=".to_owned() + MULTIPART_BOUNDARY)
+                .header("Content-Length", length)
+                .body(body)
+        },
+        _ => {
+            builder
+                .header("Content-Type", "application/json")
+                .body(hyper::Body::from(payload))
+        },
+    }?;
+
+    let req = ttime::timeout(REQUEST_TIMEOUT, api.client.request(request)).await??;
     let status = BroadcastResultInner::new(req.status(), req.headers());
-    let body = hyper::body::aggregate(req).await?;
+    let body = ttime::timeout(REQUEST_TIMEOUT, hyper::body::aggregate(req)).await??;
 
     let mut reader = body.reader();
     let mut data = Vec::new();
     reader.read_to_end(&mut data)?;
 
-    let s = String::from_utf8(data).unwrap();
+    let s = String::from_utf8_lossy(&data).into_owned();
 
-    Ok(BroadcastResultInner::from(&status, s))
+    let mut res = BroadcastResultInner::from(&status, s);
+    match (action, &res.status) {
+        // The ID that came back is the DM channel's.
+        (BroadcastAction::OpenDm, _) => res.dm_channel = res.message_id.take(),
+        (BroadcastAction::Lookup, BroadcastResultType::Success) => {
+            res.message_id = None;
+            res.channel_kind = serde_json::from_slice::<ChannelInfo>(&data).ok().map(|c| c.kind);
+        },
+        _ => (),
+    };
+    if target.webhook.is_some() {
+        // A bad webhook token only affects this subscription, not the bot.
+        match res.status {
+            BroadcastResultType::Unauthorized | BroadcastResultType::NotFound | BroadcastResultType::UnknownChannel => res.status = BroadcastResultType::UnknownWebhook,
+            _ => (),
+        };
+    }
+    Ok(res)
 
 }
 
+const REQUEST_TIMEOUT: ttime::Duration = ttime::Duration::from_secs(30);
+const MAX_ATTEMPTS: u32 = 5;
+const RETRY_BACKOFF_BASE: u64 = 1000;
+const RETRY_BACKOFF_MAX: u64 = 60 * 1000;
+
+// A request only becomes a future once it's sent, so queued channels cost no more than their row.
+type InFlight = Pin<Box<dyn Future<Output = (BroadcastInstance, BroadcastResult)> + Send>>;
+
 struct BroadcastInstance {
-    attempt: Pin<Box<dyn Future<Output= BroadcastResult> + Send>>,
-    channel_id: i64,
+    action: BroadcastAction,
+    channel: db::Channel,
+    // Set when acting on a message that was already broadcast.
+    message_id: Option<i64>,
+    failures: u32,
+    // Milliseconds to wait before the next attempt goes out.
+    backoff: u64,
+    // Already reopened the thread once, don't keep trying if it archives again.
+    unarchived: bool,
+    // Sent instead of the broadcast's payload, for messages written for one recipient.
+    payload: Option<String>,
+    // Where a DM goes, once it's been opened.
+    dm_channel: Option<i64>,
 }
 
 impl BroadcastInstance {
-    fn new(client: &Arc<HyperClient>, bot_token: &str, message_content: &str, channel_id: i64) -> Self {
+    fn new(action: BroadcastAction, channel: db::Channel, message_id: Option<i64>) -> Self {
         Self {
-            attempt: Box::pin(send_message(Arc::clone(client), bot_token.to_owned(), message_content.to_owned(), channel_id)),
-            channel_id: channel_id,
+            action,
+            channel,
+            message_id,
+            failures: 0,
+            backoff: 0,
+            unarchived: false,
+            payload: None,
+            dm_channel: None,
         }
     }
 
-    fn retry(&mut self, client: &Arc<HyperClient>, bot_token: &str, message_content: &str) {
-        self.attempt = Box::pin(send_message(Arc::clone(client), bot_token.to_owned(), message_content.to_owned(), self.channel_id));
-    } 
+    fn start(self, api: &Arc<DiscordApi>, content: &Arc<BroadcastContent>) -> InFlight {
+        let api = Arc::clone(api);
+        let content = Arc::clone(content);
+        Box::pin(async move {
+            if self.backoff > 0 {
+                ttime::sleep(ttime::Duration::from_millis(self.backoff)).await;
+            }
+            let res = send_message(api, content, &self).await;
+            (self, res)
+        })
+    }
+
+    fn retry(&mut self) {
+        self.backoff = 0;
+    }
+
+    // Whether the request sends the broadcast's payload exactly as given, rather than a version made for
+    // this channel: its own message, a role mention, or one wrapped up for a webhook or forum post.
+    fn shared_payload(&self) -> bool {
+        let forum_post = self.action == BroadcastAction::Send && self.channel.kind == db::CHANNEL_FORUM;
+        self.payload.is_none() && self.channel.webhook.is_none() && self.channel.role.is_none() && !forum_post
+    }
+
+    // Connection errors and 5xx responses back off exponentially before trying again.
+    // Returns false once the channel has used up its attempts.
+    fn retry_failed(&mut self) -> bool {
+        self.failures += 1;
+        if self.failures >= MAX_ATTEMPTS {
+            return false;
+        }
+
+        self.backoff = cmp::min(RETRY_BACKOFF_BASE << (self.failures - 1), RETRY_BACKOFF_MAX);
+        true
+    }
 }
 
 const REQUEST_COUNT: usize = 30;
+const WEBHOOK_REQUEST_COUNT: usize = 100;
+// Used for webhooks that come back limited without saying which bucket they're in.
+const WEBHOOK_BUCKET: &str = "webhook";
+const UNSUBSCRIBE_AFTER: i32 = 3;
+const CROSSPOST_MAX_WAIT: u64 = 5 * 60 * 1000;
+// Discord's global limit for bot tokens.
+const GLOBAL_REQUESTS_PER_SECOND: u64 = 50;
 
-pub struct MessageBroadcast {
-    client: Arc<HyperClient>,
-    total_requests: Vec<BroadcastInstance>,
-    ongoing_requests: Vec<BroadcastInstance>,
-    bot_token: String,
-    message_content: String,
-    timer: Option<Pin<Box<ttime::Sleep>>>,
-    db: Arc<db::DBManager>,
+#[derive(Debug, Default)]
+pub struct BroadcastReport {
+    job_id: i32,
+    results: HashMap<BroadcastResultType, u32>,
+    publish_results: HashMap<BroadcastResultType, u32>,
+    errors: u32,
+    failed: u32,
+    unsubscribed: u32,
+    retries: u32,
+    cancelled: bool,
+    // Set when an error meant no other request could succeed either.
+    aborted: Option<BroadcastResultType>,
+    unsent: u32,
+    duration: ttime::Duration,
 }
 
-impl MessageBroadcast {
-    pub fn new(db: Arc<db::DBManager>, channels: Vec<i64>, client: Arc<HyperClient>, bot_token: String, message_content: &str) -> Self {
-        println!("Starting Broadcast: {}", message_content);
-        
-        Self {
-            client: Arc::clone(&client),
-            total_requests: channels.into_iter().map(|v| BroadcastInstance::new(&client, &bot_token, message_content, v)).collect(),
-            bot_token: bot_token,
-            message_content: message_content.to_owned(),
-            ongoing_requests: Vec::new(),
-            timer: None,
-            db,
+impl BroadcastReport {
+    fn add_result(&mut self, status: &BroadcastResultType) {
+        *self.results.entry(status.clone()).or_insert(0) += 1;
+    }
+
+    fn add_publish_result(&mut self, status: &BroadcastResultType) {
+        *self.publish_results.entry(status.clone()).or_insert(0) += 1;
+    }
+}
+
+impl fmt::Display for BroadcastReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let secs = self.duration.as_secs();
+        match (&self.aborted, self.cancelled) {
+            (Some(reason), _) => writeln!(f, "Broadcast {} aborted after {}m {}s: {:?}", self.job_id, secs / 60, secs % 60, reason)?,
+            (None, true) => writeln!(f, "Broadcast {} cancelled after {}m {}s", self.job_id, secs / 60, secs % 60)?,
+            (None, false) => writeln!(f, "Broadcast {} finished in {}m {}s", self.job_id, secs / 60, secs % 60)?,
+        };
+        let mut results: Vec<_> = self.results.iter().collect();
+        results.sort_by(|a, b| b.1.cmp(a.1));
+        for (status, count) in results {
+            writeln!(f, "{:?}: {}", status, count)?;
+        }
+        let mut publish_results: Vec<_> = self.publish_results.iter().collect();
+        publish_results.sort_by(|a, b| b.1.cmp(a.1));
+        for (status, count) in publish_results {
+            writeln!(f, "Publish {:?}: {}", status, count)?;
         }
+        writeln!(f, "Request Errors: {}", self.errors)?;
+        writeln!(f, "Failed After Retries: {}", self.failed)?;
+        writeln!(f, "Unsubscribed: {}", self.unsubscribed)?;
+        if self.cancelled || self.aborted.is_some() {
+            writeln!(f, "Unsent: {}", self.unsent)?;
+        }
+        write!(f, "Retries: {}", self.retries)
     }
+}
 
-    fn start_waiting_retry(&mut self, cx: &mut Context, time_until: u64) {
-        if let Some(_) = self.timer {
-            // Already waiting. Don't replace timer.
-            return;
+// How far along a broadcast is, shared with whoever wants to report on it or cancel it.
+pub struct BroadcastStatus {
+    job_id: i32,
+    description: String,
+    total: usize,
+    remaining: AtomicUsize,
+    cancelled: AtomicBool,
+    waker: AtomicWaker,
+}
+
+impl BroadcastStatus {
+    pub fn job_id(&self) -> i32 {
+        self.job_id
+    }
+
+    // Requests already sent are allowed to finish, everything still queued is dropped.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+        // Might be sleeping on a rate limit, wake it so it notices.
+        self.waker.wake();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+impl fmt::Display for BroadcastStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let remaining = self.remaining.load(Ordering::Relaxed);
+        write!(f, "{}: {}/{} channels", self.description, self.total.saturating_sub(remaining), self.total)?;
+        if self.is_cancelled() {
+            write!(f, " (cancelling)")?;
         }
+        Ok(())
+    }
+}
 
-        let mut timer = Box::pin(ttime::sleep(ttime::Duration::from_millis(time_until + 200)));
-        // Have to poll the timer to register an interest.
-        match timer.as_mut().poll(cx) {
-            Poll::Ready(_) => (),
-            Poll::Pending => (),
-        };
-        self.timer = Some(timer);
+// Where a broadcast records what happened to each channel. Writes are spawned off, a broadcast never waits on them.
+pub trait BroadcastLog: Send + Sync {
+    fn unsubscribe(self: Arc<Self>, channel_id: i64);
+    fn add_failure(self: Arc<Self>, channel_id: i64);
+    fn reset_failures(self: Arc<Self>, channel_id: i64);
+    fn store_kind(self: Arc<Self>, channel_id: i64, kind: i16);
+    fn store_delivery(self: Arc<Self>, job_id: i32, channel_id: i64, status: db::DeliveryStatus, message_id: Option<i64>);
+    fn finish_job(self: Arc<Self>, job_id: i32, cancelled: bool);
+}
+
+impl BroadcastLog for db::DBManager {
+    fn unsubscribe(self: Arc<Self>, channel_id: i64) {
+        tokio::spawn(async move {
+            self.delete_channel(channel_id).await.unwrap();
+        });
     }
 
-    fn unsubscribe_instance(&self, instance: &BroadcastInstance) {
-        let db = Arc::clone(&self.db);
-        let channel_id = instance.channel_id;
+    fn add_failure(self: Arc<Self>, channel_id: i64) {
         tokio::spawn(async move {
-            db.delete_channel(channel_id).await.unwrap();
+            if let Err(e) = self.add_channel_failure(channel_id).await {
+                println!("Could not record failure for {}: {}", channel_id, e);
+            }
         });
     }
-}
 
-impl Future for MessageBroadcast {
-    type Output = ();
+    fn reset_failures(self: Arc<Self>, channel_id: i64) {
+        tokio::spawn(async move {
+            if let Err(e) = self.reset_channel_failures(channel_id).await {
+                println!("Could not reset failures for {}: {}", channel_id, e);
+            }
+        });
+    }
 
-    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
-        // Find out if waiting on rate limits
-        let waiting = match &mut self.timer {
-            Some(val) => {
-                match val.as_mut().poll(cx) {
-                    Poll::Ready(_) => {
-                        self.timer = None;
-                        false
-                    },
-                    Poll::Pending => true,
-                }
-            },
-            None => false,
-        };
+    fn store_kind(self: Arc<Self>, channel_id: i64, kind: i16) {
+        tokio::spawn(async move {
+            if let Err(e) = self.set_channel_kind(channel_id, kind).await {
+                println!("Could not store the type of {}: {}", channel_id, e);
+            }
+        });
+    }
 
-        // Add more requests to concurrent queue
-        if waiting == false {
-            let new_requests = cmp::min(self.total_requests.len(), REQUEST_COUNT - self.ongoing_requests.len());
-            for _ in 0..new_requests {
-                let req = self.total_requests.remove(0);
-                self.ongoing_requests.push(req);
+    fn store_delivery(self: Arc<Self>, job_id: i32, channel_id: i64, status: db::DeliveryStatus, message_id: Option<i64>) {
+        tokio::spawn(async move {
+            if let Err(e) = self.set_delivery_status(job_id, channel_id, status, message_id).await {
+                println!("Could not record delivery to {}: {}", channel_id, e);
             }
-        }
+        });
+    }
 
-        // Process ongoing requests
-        let mut i = 0;
-        while i != self.ongoing_requests.len() {
-            match self.ongoing_requests[i].attempt.as_mut().poll(cx) {
-                Poll::Ready(Ok(res)) => {
-                    let mut request = self.ongoing_requests.remove(i);
-                    match res.status {
-                        BroadcastResultType::Success => {
-                            // Message delivered, instance removed from queue.
-                            // Do nothing here.
-                        },
-                        BroadcastResultType::MissingAccess | BroadcastResultType::MissingPermissions | BroadcastResultType::UnknownChannel => {
-                            // Bot's been removed from channel/guild
-                            // Unsubscribe this channel
-                            self.unsubscribe_instance(&request);
-                        },
-                        BroadcastResultType::RateLimited => {
-                            // Message didn't get delivered due to rate limits
-                            // Likely returned while other requests were processing.
-                            // Start it again and move it to the end of the queue
-                            request.retry(&self.client, &self.bot_token, &self.message_content);
-                            self.total_requests.push(request);
-                            // Start wait timer if not already started
-                            if res.rate_limit_retry != 0 {
-                                self.start_waiting_retry(cx, res.rate_limit_retry);
-                            } else {
-                                // No retry value, not sure why.
-                                // Just wait 1s
+    fn finish_job(self: Arc<Self>, job_id: i32, cancelled: bool) {
+        tokio::spawn(async move {
+            let res = match cancelled {
+                true => self.cancel_broadcast_job(job_id).await,
+                false => self.finish_broadcast_job(job_id).await,
+            };
+            if let Err(e) = res {
+                println!("Could not finish broadcast job {}: {}", job_id, e);
+            }
+        });
+    }
+}
+
+// Retractions run under the original job, so they get their own IDs from here to be cancelled by.
+// Counting down keeps them from clashing with job IDs.
+static RETRACTION_ID: AtomicI32 = AtomicI32::new(-1);
+
+pub struct MessageBroadcast {
+    api: Arc<DiscordApi>,
+    total_requests: VecDeque<BroadcastInstance>,
+    // Webhooks have their own rate limits, so they're queued and sent separately.
+    webhook_requests: VecDeque<BroadcastInstance>,
+    ongoing_requests: FuturesUnordered<InFlight>,
+    ongoing_webhooks: usize,
+    content: Arc<BroadcastContent>,
+    job_id: i32,
+    timer: Option<Pin<Box<ttime::Sleep>>>,
+    bucket_timer: Option<Pin<Box<ttime::Sleep>>>,
+    db: Arc<dyn BroadcastLog>,
+    // Set on the first poll, time spent queued behind other broadcasts doesn't count.
+    started: Option<ttime::Instant>,
+    report: BroadcastReport,
+    status: Arc<BroadcastStatus>,
+    // Dry runs and DMs don't write anything to the database.
+    tracked: bool,
+}
+
+impl MessageBroadcast {
+    pub fn new(db: Arc<dyn BroadcastLog>, job_id: i32, channels: Vec<db::Channel>, api: Arc<DiscordApi>, payload: &str, attachment: Option<Attachment>) -> Self {
+        println!("Starting Broadcast {}: {}", job_id, payload);
+        let content = Arc::new(BroadcastContent {
+            payload: payload.to_owned(),
+            thread_payload: thread_payload(payload),
+            webhook_payload: webhook_payload(payload, true),
+            attachment,
+            dry_run: None,
+        });
+        // Older subscriptions don't know what kind of channel they are, so they're looked up before sending.
+        let requests = channels.into_iter().map(|v| match v.kind {
+            db::CHANNEL_UNKNOWN => BroadcastInstance::new(BroadcastAction::Lookup, v, None),
+            _ => BroadcastInstance::new(BroadcastAction::Send, v, None),
+        }).collect();
+
+        Self::from_requests(db, job_id, job_id, api, content, requests, format!("Broadcast {}", job_id))
+    }
+
+    // Goes through the whole broadcast without sending anything or touching the database,
+    // except for a real message to the test channel.
+    pub fn dry_run(db: Arc<dyn BroadcastLog>, mut channels: Vec<db::Channel>, api: Arc<DiscordApi>, payload: &str, test_channel: i64) -> Self {
+        println!("Starting Dry Run: {}", payload);
+        channels.retain(|c| c.discord != test_channel);
+        channels.push(db::Channel {
+            discord: test_channel,
+            failures: 0,
+            kind: db::CHANNEL_TEXT,
+            webhook: None,
+            role: None,
+        });
+        let content = Arc::new(BroadcastContent {
+            payload: payload.to_owned(),
+            thread_payload: thread_payload(payload),
+            webhook_payload: webhook_payload(payload, true),
+            attachment: None,
+            dry_run: Some(test_channel),
+        });
+        let requests = channels.into_iter().map(|v| BroadcastInstance::new(BroadcastAction::Send, v, None)).collect();
+
+        Self::from_requests(db, 0, 0, api, content, requests, "Dry Run".to_owned())
+    }
+
+    // Edits every message sent by an earlier broadcast, or deletes them if there's no new payload.
+    pub fn retract(db: Arc<dyn BroadcastLog>, job_id: i32, messages: Vec<(db::Channel, i64)>, api: Arc<DiscordApi>, edit: Option<String>) -> Self {
+        println!("Retracting Broadcast {}: {} messages", job_id, messages.len());
+        let action = match edit {
+            Some(_) => BroadcastAction::Edit,
+            None => BroadcastAction::Delete,
+        };
+        let edit = edit.unwrap_or_default();
+        let content = Arc::new(BroadcastContent {
+            webhook_payload: webhook_payload(&edit, false),
+            payload: edit,
+            thread_payload: String::new(),
+            attachment: None,
+            dry_run: None,
+        });
+        let requests = messages.into_iter().map(|(c, m)| BroadcastInstance::new(action, c, Some(m))).collect();
+
+        let id = RETRACTION_ID.fetch_sub(1, Ordering::Relaxed);
+        let description = match action {
+            BroadcastAction::Edit => format!("Edit {} (ID {})", job_id, id),
+            _ => format!("Retract {} (ID {})", job_id, id),
+        };
+        Self::from_requests(db, job_id, id, api, content, requests, description)
+    }
+
+    // Sends each user their own message, like the wishlist notifications.
+    pub fn direct_messages(db: Arc<dyn BroadcastLog>, api: Arc<DiscordApi>, messages: Vec<(i64, String)>, description: &str) -> Self {
+        println!("Starting {}: {} users", description, messages.len());
+        let content = Arc::new(BroadcastContent {
+            payload: String::new(),
+            thread_payload: String::new(),
+            webhook_payload: String::new(),
+            attachment: None,
+            dry_run: None,
+        });
+        let requests = messages.into_iter().map(|(user, payload)| {
+            let channel = db::Channel {
+                discord: user,
+                failures: 0,
+                kind: db::CHANNEL_DM,
+                webhook: None,
+                role: None,
+            };
+            let mut instance = BroadcastInstance::new(BroadcastAction::OpenDm, channel, None);
+            instance.payload = Some(payload);
+            instance
+        }).collect();
+
+        let mut broadcast = Self::from_requests(db, 0, 0, api, content, requests, description.to_owned());
+        broadcast.tracked = false;
+        broadcast
+    }
+
+    // The status ID is what the broadcast is cancelled by, the job ID is what its deliveries are stored under.
+    fn from_requests(db: Arc<dyn BroadcastLog>, job_id: i32, status_id: i32, api: Arc<DiscordApi>, content: Arc<BroadcastContent>, requests: VecDeque<BroadcastInstance>, description: String) -> Self {
+        let status = Arc::new(BroadcastStatus {
+            job_id: status_id,
+            description,
+            total: requests.len(),
+            remaining: AtomicUsize::new(requests.len()),
+            cancelled: AtomicBool::new(false),
+            waker: AtomicWaker::new(),
+        });
+
+        let tracked = content.dry_run.is_none();
+        let (webhook_requests, total_requests): (VecDeque<_>, VecDeque<_>) = requests.into_iter().partition(|r| r.channel.webhook.is_some());
+        Self {
+            total_requests,
+            webhook_requests,
+            ongoing_webhooks: 0,
+            api,
+            content,
+            job_id,
+            ongoing_requests: FuturesUnordered::new(),
+            timer: None,
+            bucket_timer: None,
+            db,
+            started: None,
+            report: BroadcastReport {
+                job_id,
+                ..Default::default()
+            },
+            status,
+            tracked,
+        }
+    }
+
+    pub fn status(&self) -> Arc<BroadcastStatus> {
+        Arc::clone(&self.status)
+    }
+
+    fn start_waiting_retry(&mut self, cx: &mut Context, time_until: u64) {
+        self.api.limiter().pause_global(ttime::Duration::from_millis(time_until + 200));
+        self.wait_global(cx);
+    }
+
+    // Whether every request is paused on the global limit, which another broadcast may have hit.
+    // Makes sure we're woken up once the pause is over.
+    fn wait_global(&mut self, cx: &mut Context) -> bool {
+        let reset = match self.api.limiter().global_reset() {
+            Some(r) => r,
+            None => {
+                self.timer = None;
+                return false;
+            },
+        };
+        let replace = match &self.timer {
+            Some(timer) => timer.deadline() != reset,
+            None => true,
+        };
+        if replace {
+            self.timer = Some(Box::pin(ttime::sleep_until(reset)));
+        }
+
+        if let Some(timer) = &mut self.timer {
+            // Have to poll the timer to register an interest.
+            if timer.as_mut().poll(cx).is_ready() {
+                self.timer = None;
+                return false;
+            }
+        }
+        true
+    }
+
+    fn start_bucket_timer(&mut self, cx: &mut Context, wait: ttime::Duration) {
+        let deadline = ttime::Instant::now() + wait;
+        if let Some(timer) = &self.bucket_timer {
+            if timer.deadline() <= deadline {
+                // Already waking up before this bucket resets.
+                return;
+            }
+        }
+
+        let mut timer = Box::pin(ttime::sleep_until(deadline));
+        match timer.as_mut().poll(cx) {
+            Poll::Ready(_) => (),
+            Poll::Pending => (),
+        };
+        self.bucket_timer = Some(timer);
+    }
+
+
+    fn unsubscribe_instance(&mut self, instance: &BroadcastInstance) {
+        self.report.unsubscribed += 1;
+        if !self.tracked {
+            return;
+        }
+        Arc::clone(&self.db).unsubscribe(instance.channel.discord);
+        self.record_delivery(instance, db::DeliveryStatus::Unsubscribed);
+    }
+
+    // A deleted channel is gone for good, but missing permissions are often a guild admin mid-change.
+    // Those channels are only dropped after failing several broadcasts in a row.
+    fn channel_failed(&mut self, instance: &BroadcastInstance, status: &BroadcastResultType) {
+        if !self.tracked {
+            return;
+        }
+        if let BroadcastResultType::UnknownChannel | BroadcastResultType::UnknownWebhook = status {
+            self.unsubscribe_instance(instance);
+            return;
+        }
+        if instance.channel.failures + 1 >= UNSUBSCRIBE_AFTER {
+            self.unsubscribe_instance(instance);
+            return;
+        }
+
+        Arc::clone(&self.db).add_failure(instance.channel.discord);
+        self.record_delivery(instance, db::DeliveryStatus::Failed);
+    }
+
+    fn reset_failures(&self, instance: &BroadcastInstance) {
+        if !self.tracked {
+            return;
+        }
+        Arc::clone(&self.db).reset_failures(instance.channel.discord);
+    }
+
+    fn set_channel_kind(&self, instance: &BroadcastInstance) {
+        if !self.tracked {
+            return;
+        }
+        Arc::clone(&self.db).store_kind(instance.channel.discord, instance.channel.kind);
+    }
+
+    fn record_delivery(&self, instance: &BroadcastInstance, status: db::DeliveryStatus) {
+        self.record_message(instance, status, None);
+    }
+
+    fn record_message(&self, instance: &BroadcastInstance, status: db::DeliveryStatus, message_id: Option<i64>) {
+        if !self.tracked {
+            return;
+        }
+        Arc::clone(&self.db).store_delivery(self.job_id, instance.channel.discord, status, message_id);
+    }
+
+    fn retry_failed_instance(&mut self, mut instance: BroadcastInstance) {
+        if instance.retry_failed() {
+            self.report.retries += 1;
+            self.queue(instance);
+            return;
+        }
+
+        println!("Giving up on channel {} after {} attempts", instance.channel.discord, MAX_ATTEMPTS);
+        self.report.failed += 1;
+        if instance.action.delivers() {
+            self.record_delivery(&instance, db::DeliveryStatus::Failed);
+        }
+    }
+
+    fn finish_job(&self) {
+        if !self.tracked {
+            return;
+        }
+        if let Some(BroadcastResultType::Unauthorized) = self.report.aborted {
+            // Left unfinished, so the remaining deliveries resume once there's a working token.
+            return;
+        }
+        let cancelled = self.report.cancelled || self.report.aborted.is_some();
+        Arc::clone(&self.db).finish_job(self.job_id, cancelled);
+    }
+
+    fn abort(&mut self, status: &BroadcastResultType) {
+        if self.report.aborted.is_none() {
+            println!("!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!");
+            match status {
+                BroadcastResultType::Unauthorized => {
+                    println!("!!! Broadcast {} Aborted: Discord rejected the bot token !!!", self.job_id);
+                    println!("!!! Replace DISCORD_TOKEN and restart, pending deliveries will resume !!!");
+                },
+                _ => println!("!!! Broadcast {} Aborted: {:?}, payload: {} !!!", self.job_id, status, self.content.payload),
+            };
+            println!("!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!");
+        }
+        if let BroadcastResultType::Unauthorized = status {
+            self.api.unauthorized.store(true, Ordering::Relaxed);
+        }
+        self.report.aborted = Some(status.clone());
+    }
+
+    fn drop_queued(&mut self) {
+        let queued = self.total_requests.len() + self.webhook_requests.len();
+        if queued > 0 {
+            println!("Broadcast {} Stopped: Dropping {} queued requests", self.job_id, queued);
+        }
+        self.report.unsent += queued as u32;
+        self.total_requests.clear();
+        self.webhook_requests.clear();
+    }
+
+    fn queue(&mut self, instance: BroadcastInstance) {
+        match instance.channel.webhook {
+            Some(_) => self.webhook_requests.push_back(instance),
+            None => self.total_requests.push_back(instance),
+        };
+    }
+
+    // Moves queued requests into the concurrent ones, holding back any whose bucket is empty.
+    // Returns how long until the first held back bucket resets.
+    fn feed(&mut self, webhooks: bool) -> Option<ttime::Duration> {
+        let limit = match webhooks {
+            true => WEBHOOK_REQUEST_COUNT,
+            false => REQUEST_COUNT,
+        };
+        // Held back requests go to the end of the queue, each one is only looked at once per poll.
+        let mut checks = match webhooks {
+            true => self.webhook_requests.len(),
+            false => self.total_requests.len(),
+        };
+        let mut bucket_wait = None;
+        while checks > 0 {
+            let ongoing = match webhooks {
+                true => self.ongoing_webhooks,
+                false => self.ongoing_requests.len() - self.ongoing_webhooks,
+            };
+            if ongoing >= limit {
+                break;
+            }
+            checks -= 1;
+            let next = match webhooks {
+                true => self.webhook_requests.pop_front(),
+                false => self.total_requests.pop_front(),
+            };
+            let req = match next {
+                Some(r) => r,
+                None => break,
+            };
+            let channel_id = req.channel.discord;
+            let route = req.action.route(req.channel.kind);
+            let wait_time = self.api.limiter().wait_time(route, channel_id);
+            match wait_time {
+                Some(wait) => {
+                    bucket_wait = Some(match bucket_wait {
+                        Some(w) => cmp::min(w, wait),
+                        None => wait,
+                    });
+                    self.queue(req);
+                },
+                None => {
+                    self.api.limiter().acquire(route, channel_id);
+                    if webhooks {
+                        self.ongoing_webhooks += 1;
+                    }
+                    let attempt = req.start(&self.api, &self.content);
+                    self.ongoing_requests.push(attempt);
+                },
+            };
+        }
+
+        bucket_wait
+    }
+}
+
+impl Future for MessageBroadcast {
+    type Output = BroadcastReport;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        self.status.waker.register(cx.waker());
+        let started = *self.started.get_or_insert_with(ttime::Instant::now);
+        if self.api.is_unauthorized() && self.report.aborted.is_none() {
+            // Another broadcast already found out the token is bad.
+            self.abort(&BroadcastResultType::Unauthorized);
+        }
+        if self.status.is_cancelled() {
+            self.report.cancelled = true;
+        }
+        if self.report.cancelled || self.report.aborted.is_some() {
+            self.drop_queued();
+        }
+
+        // Find out if waiting on rate limits
+        let waiting = self.wait_global(cx);
+
+        if let Some(val) = &mut self.bucket_timer {
+            if val.as_mut().poll(cx).is_ready() {
+                self.bucket_timer = None;
+            }
+        }
+
+        // Add more requests to concurrent queue. Webhooks don't count against the global limit,
+        // but a Cloudflare ban holds them back until it's over.
+        let ban_wait = self.api.limiter().ban_wait();
+        let mut bucket_wait = match ban_wait {
+            Some(wait) => Some(wait),
+            None => self.feed(true),
+        };
+        if waiting == false {
+            bucket_wait = match (bucket_wait, self.feed(false)) {
+                (Some(a), Some(b)) => Some(cmp::min(a, b)),
+                (a, b) => a.or(b),
+            };
+        }
+        if let Some(wait) = bucket_wait {
+            self.start_bucket_timer(cx, wait);
+        }
+
+        // Process ongoing requests
+        let mut completed = false;
+        while let Poll::Ready(Some((mut request, res))) = self.ongoing_requests.poll_next_unpin(cx) {
+            completed = true;
+            if request.channel.webhook.is_some() {
+                self.ongoing_webhooks -= 1;
+            }
+            match res {
+                Ok(res) => {
+                    let action = request.action;
+                    let route = action.route(request.channel.kind);
+                    self.api.limiter().update(route, request.channel.discord, &res.rate_limit_bucket, res.rate_limit_left, res.rate_limit_reset_after);
+                    if let Some(kind) = res.channel_kind {
+                        request.channel.kind = kind;
+                        self.set_channel_kind(&request);
+                    }
+                    match action {
+                        // Publishing doesn't affect whether the message was delivered, so it's reported separately.
+                        BroadcastAction::Crosspost => self.report.add_publish_result(&res.status),
+                        // Opening a DM or looking up a channel is only worth reporting if it didn't work, the message is counted on its own.
+                        BroadcastAction::OpenDm | BroadcastAction::Lookup if res.status == BroadcastResultType::Success => (),
+                        _ => self.report.add_result(&res.status),
+                    };
+                    match res.status {
+                        BroadcastResultType::Success => {
+                            // Message delivered, instance removed from queue.
+                            match action {
+                                BroadcastAction::Send => {
+                                    self.record_message(&request, db::DeliveryStatus::Delivered, res.message_id);
+                                    if request.channel.failures > 0 {
+                                        self.reset_failures(&request);
+                                    }
+                                    if let (db::CHANNEL_NEWS, Some(message_id)) = (request.channel.kind, res.message_id) {
+                                        let publish = BroadcastInstance::new(BroadcastAction::Crosspost, request.channel.clone(), Some(message_id));
+                                        self.queue(publish);
+                                    }
+                                },
+                                BroadcastAction::Delete => self.record_delivery(&request, db::DeliveryStatus::Retracted),
+                                BroadcastAction::Unarchive => {
+                                    let mut send = BroadcastInstance::new(BroadcastAction::Send, request.channel.clone(), None);
+                                    send.unarchived = true;
+                                    self.queue(send);
+                                },
+                                BroadcastAction::OpenDm => match res.dm_channel {
+                                    Some(dm_channel) => {
+                                        request.action = BroadcastAction::Send;
+                                        request.dm_channel = Some(dm_channel);
+                                        self.queue(request);
+                                    },
+                                    None => println!("No DM channel for {}", request.channel.discord),
+                                },
+                                // Sent like a text channel if the type didn't come back, and looked up again next time.
+                                BroadcastAction::Lookup => {
+                                    request.action = BroadcastAction::Send;
+                                    self.queue(request);
+                                },
+                                BroadcastAction::Edit | BroadcastAction::Crosspost => (),
+                            };
+                        },
+                        BroadcastResultType::MissingAccess | BroadcastResultType::MissingPermissions | BroadcastResultType::UnknownChannel | BroadcastResultType::UnknownWebhook => {
+                            // Bot's been removed from channel/guild, or lost permissions
+                            // Only new messages count against the subscription.
+                            match action.delivers() {
+                                true => self.channel_failed(&request, &res.status),
+                                false => println!("Could not {:?} message in {}: {:?}", action, request.channel.discord, res.status),
+                            };
+                        },
+                        BroadcastResultType::ThreadArchived if action == BroadcastAction::Send && !request.unarchived => {
+                            // Threads archive themselves after a while without messages.
+                            // Anyone who can post can reopen one, unless a moderator locked it.
+                            let unarchive = BroadcastInstance::new(BroadcastAction::Unarchive, request.channel.clone(), None);
+                            self.queue(unarchive);
+                        },
+                        BroadcastResultType::CloudflareBan => {
+                            // The whole IP is banned, webhooks included. Everything waits it out.
+                            request.retry();
+                            self.report.retries += 1;
+                            self.queue(request);
+                            self.api.limiter().ban(ttime::Duration::from_millis(res.rate_limit_retry));
+                            self.wait_global(cx);
+                            println!("Cloudflare Ban: Pausing Broadcast for {}ms", res.rate_limit_retry);
+                        },
+                        BroadcastResultType::RateLimited | BroadcastResultType::GlobalRateLimited if request.channel.webhook.is_some() => {
+                            // Limits are per webhook, so only this one is held back.
+                            request.retry();
+                            self.report.retries += 1;
+                            let channel_id = request.channel.discord;
+                            self.queue(request);
+                            let bucket = res.rate_limit_bucket.clone().or_else(|| Some(WEBHOOK_BUCKET.to_owned()));
+                            self.api.limiter().update(route, channel_id, &bucket, 0, cmp::max(res.rate_limit_retry, 1000));
+                            println!("Webhook Rate Limited: Wait Until {}", res.rate_limit_retry);
+                        },
+                        BroadcastResultType::RateLimited if action == BroadcastAction::Crosspost && res.rate_limit_retry > CROSSPOST_MAX_WAIT => {
+                            // Announcement channels can only publish a handful of messages an hour.
+                            // Not worth holding up the whole broadcast for.
+                            println!("Publish Rate Limited in {}: Skipping", request.channel.discord);
+                        },
+                        BroadcastResultType::RateLimited => {
+                            // Message didn't get delivered due to rate limits
+                            // Likely returned while other requests were processing.
+                            // Start it again and move it to the end of the queue
+                            request.retry();
+                            self.report.retries += 1;
+                            let channel_id = request.channel.discord;
+                            self.queue(request);
+                            if res.rate_limit_bucket.is_some() {
+                                // Only this bucket is exhausted, hold back its requests until the retry
+                                self.api.limiter().update(route, channel_id, &res.rate_limit_bucket, 0, res.rate_limit_retry);
+                            } else if res.rate_limit_retry != 0 {
+                                // Start wait timer if not already started
+                                self.start_waiting_retry(cx, res.rate_limit_retry);
+                            } else {
+                                // No retry value, not sure why.
+                                // Just wait 1s
                                 self.start_waiting_retry(cx, 1000);
                             }
                             // Just monitoring the rate limits for awhile, seeing how many repeats
                             println!("Request Rate Limited: Wait Until {}", res.rate_limit_retry);
                         },
-                        BroadcastResultType::Forbidden | BroadcastResultType::NotFound | BroadcastResultType::Unknown => {
+                        BroadcastResultType::GlobalRateLimited => {
+                            // Every request counts against the global limit, so pause the whole broadcast.
+                            // In-flight requests that come back limited land here too and get queued again.
+                            request.retry();
+                            self.report.retries += 1;
+                            self.queue(request);
+                            let retry = match res.rate_limit_retry {
+                                0 => 1000,
+                                r => r,
+                            };
+                            self.start_waiting_retry(cx, retry);
+                            println!("Global Rate Limit Hit: Pausing Broadcast for {}ms", retry);
+                        },
+                        BroadcastResultType::ServerError => {
+                            // Discord is having trouble, try again shortly.
+                            println!("Server Error: {:#?}", res);
+                            self.retry_failed_instance(request);
+                        },
+                        BroadcastResultType::InvalidFormBody if !request.shared_payload() => {
+                            // Only this channel's version of the payload was rejected, the rest can still go out.
+                            println!("Invalid Payload for {}: {:#?}", request.channel.discord, res);
+                            if action.delivers() {
+                                self.record_delivery(&request, db::DeliveryStatus::Failed);
+                            }
+                        },
+                        BroadcastResultType::Unauthorized | BroadcastResultType::InvalidFormBody => {
+                            // Every other request is going to fail the same way, stop the broadcast.
+                            // The delivery isn't recorded, so it can be picked up again.
+                            self.abort(&res.status);
+                        },
+                        BroadcastResultType::CannotMessageUser => {
+                            // The user has DMs from server members turned off, nothing to be done.
+                        },
+                        BroadcastResultType::BadRequest | BroadcastResultType::ThreadArchived | BroadcastResultType::Forbidden | BroadcastResultType::NotFound | BroadcastResultType::Unknown => {
                             // An unknown error, just log and move on.
                             println!("Request Error: {:#?}", res);
+                            if action.delivers() {
+                                self.record_delivery(&request, db::DeliveryStatus::Failed);
+                            }
                         },
                     };
                 },
-                Poll::Pending => {
-                    i += 1;
-                },
-                Poll::Ready(Err(_)) => {
-                    self.ongoing_requests.remove(i);
+                Err(e) => {
+                    // Connection reset, TLS failure, timeout and the like.
+                    self.report.errors += 1;
+                    println!("Request Failed for {}: {}", request.channel.discord, e);
+                    self.retry_failed_instance(request);
                 }
             };
         }
-        if self.ongoing_requests.len() <= 0 && self.total_requests.len() <= 0 {
-            println!("Broadcast Finished");
-            return Poll::Ready(());
+        let remaining = self.total_requests.len() + self.webhook_requests.len() + self.ongoing_requests.len();
+        self.status.remaining.store(remaining, Ordering::Relaxed);
+        if remaining == 0 {
+            self.finish_job();
+            let mut report = std::mem::take(&mut self.report);
+            report.duration = started.elapsed();
+            return Poll::Ready(report);
+        }
+        if completed {
+            // Slots have freed up, come back around to fill them.
+            cx.waker().wake_by_ref();
         }
         Poll::Pending
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    struct MockResponse {
+        status: u16,
+        headers: Vec<(&'static str, String)>,
+        body: String,
+    }
+
+    fn respond(status: u16, body: &str) -> MockResponse {
+        MockResponse {
+            status,
+            headers: Vec::new(),
+            body: body.to_owned(),
+        }
+    }
+
+    fn with_header(mut response: MockResponse, name: &'static str, value: &str) -> MockResponse {
+        response.headers.push((name, value.to_owned()));
+        response
+    }
+
+    // Gets the method, the path without the leading slash, and how many times that path has been requested, this one included.
+    type Script = dyn Fn(&str, &str, usize) -> MockResponse + Send + Sync;
+
+    struct MockRequest {
+        path: String,
+        authorized: bool,
+        body: String,
+    }
+
+    #[derive(Default)]
+    struct MockLog {
+        requests: Vec<MockRequest>,
+        // Keyed by method and path.
+        seen: HashMap<(String, String), usize>,
+    }
+
+    // Stands in for Discord, answering every request from a script.
+    struct MockServer {
+        base_url: String,
+        log: Arc<SMutex<MockLog>>,
+    }
+
+    impl MockServer {
+        async fn start<F: Fn(&str, &str, usize) -> MockResponse + Send + Sync + 'static>(script: F) -> Self {
+            Self::start_with(script, true).await
+        }
+
+        // Nothing is logged, and the script is never told how often a path was seen, so long benchmarks only measure the broadcast.
+        async fn unlogged<F: Fn(&str, &str, usize) -> MockResponse + Send + Sync + 'static>(script: F) -> Self {
+            Self::start_with(script, false).await
+        }
+
+        async fn start_with<F: Fn(&str, &str, usize) -> MockResponse + Send + Sync + 'static>(script: F, logged: bool) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let base_url = format!("http://{}/", listener.local_addr().unwrap());
+            let log = Arc::new(SMutex::new(MockLog::default()));
+            let script: Arc<Script> = Arc::new(script);
+            let server_log = match logged {
+                true => Some(Arc::clone(&log)),
+                false => None,
+            };
+            tokio::spawn(async move {
+                while let Ok((socket, _)) = listener.accept().await {
+                    tokio::spawn(serve(socket, Arc::clone(&script), server_log.clone()));
+                }
+            });
+
+            Self {
+                base_url,
+                log,
+            }
+        }
+
+        fn count(&self, method: &str, path: &str) -> usize {
+            let log = self.log.lock().unwrap();
+            log.seen.get(&(method.to_owned(), path.to_owned())).copied().unwrap_or(0)
+        }
+
+        fn total(&self) -> usize {
+            self.log.lock().unwrap().requests.len()
+        }
+    }
+
+    // Hyper keeps connections alive, so each one is read as a series of requests.
+    async fn serve(mut socket: TcpStream, script: Arc<Script>, log: Option<Arc<SMutex<MockLog>>>) {
+        let mut buf: Vec<u8> = Vec::new();
+        let mut chunk = [0u8; 4096];
+        loop {
+            let head_end = loop {
+                if let Some(i) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                    break i + 4;
+                }
+                match socket.read(&mut chunk).await {
+                    Ok(0) | Err(_) => return,
+                    Ok(n) => buf.extend_from_slice(&chunk[..n]),
+                };
+            };
+            let head = String::from_utf8_lossy(&buf[..head_end]).into_owned();
+            let mut length = 0;
+            let mut authorized = false;
+            for line in head.lines().skip(1) {
+                let mut header = line.splitn(2, ':');
+                if let (Some(name), Some(value)) = (header.next(), header.next()) {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        length = value.trim().parse().unwrap_or(0);
+                    }
+                    if name.eq_ignore_ascii_case("authorization") {
+                        authorized = true;
+                    }
+                }
+            }
+            while buf.len() < head_end + length {
+                match socket.read(&mut chunk).await {
+                    Ok(0) | Err(_) => return,
+                    Ok(n) => buf.extend_from_slice(&chunk[..n]),
+                };
+            }
+            let body = String::from_utf8_lossy(&buf[head_end..head_end + length]).into_owned();
+            buf.drain(..head_end + length);
+
+            let mut request_line = head.split_whitespace();
+            let method = request_line.next().unwrap_or_default().to_owned();
+            let target = request_line.next().unwrap_or_default();
+            let path = target.trim_start_matches('/').split('?').next().unwrap_or_default().to_owned();
+            let seen = match &log {
+                Some(log) => {
+                    let mut log = log.lock().unwrap();
+                    let seen = log.seen.entry((method.clone(), path.clone())).or_insert(0);
+                    *seen += 1;
+                    let seen = *seen;
+                    log.requests.push(MockRequest {
+                        path: path.clone(),
+                        authorized,
+                        body,
+                    });
+                    seen
+                },
+                None => 0,
+            };
+
+            let response = script(&method, &path, seen);
+            let mut out = format!("HTTP/1.1 {} Mock\r\nContent-Length: {}\r\n", response.status, response.body.len());
+            for (name, value) in &response.headers {
+                out += &format!("{}: {}\r\n", name, value);
+            }
+            out += "\r\n";
+            out += &response.body;
+            if socket.write_all(out.as_bytes()).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    fn test_api(base_url: &str) -> Arc<DiscordApi> {
+        let client = hyper::Client::builder().build(hyper_tls::HttpsConnector::new());
+        Arc::new(DiscordApi::new(Arc::new(client), "token".to_owned(), base_url))
+    }
+
+    #[derive(Default)]
+    struct Recorded {
+        unsubscribed: Vec<i64>,
+        failures: HashMap<i64, i32>,
+        kinds: HashMap<i64, i16>,
+        deliveries: HashMap<i64, db::DeliveryStatus>,
+        // Whether the job was cancelled, once it's finished.
+        finished: Option<bool>,
+    }
+
+    // Keeps what a broadcast records in memory, in place of the database.
+    #[derive(Default)]
+    struct TestLog {
+        recorded: SMutex<Recorded>,
+    }
+
+    impl TestLog {
+        fn recorded(&self) -> MutexGuard<'_, Recorded> {
+            self.recorded.lock().unwrap()
+        }
+    }
+
+    impl BroadcastLog for TestLog {
+        fn unsubscribe(self: Arc<Self>, channel_id: i64) {
+            self.recorded().unsubscribed.push(channel_id);
+        }
+
+        fn add_failure(self: Arc<Self>, channel_id: i64) {
+            *self.recorded().failures.entry(channel_id).or_insert(0) += 1;
+        }
+
+        fn reset_failures(self: Arc<Self>, channel_id: i64) {
+            self.recorded().failures.insert(channel_id, 0);
+        }
+
+        fn store_kind(self: Arc<Self>, channel_id: i64, kind: i16) {
+            self.recorded().kinds.insert(channel_id, kind);
+        }
+
+        fn store_delivery(self: Arc<Self>, _job_id: i32, channel_id: i64, status: db::DeliveryStatus, _message_id: Option<i64>) {
+            self.recorded().deliveries.insert(channel_id, status);
+        }
+
+        fn finish_job(self: Arc<Self>, _job_id: i32, cancelled: bool) {
+            self.recorded().finished = Some(cancelled);
+        }
+    }
+
+    fn test_log() -> Arc<TestLog> {
+        Arc::new(TestLog::default())
+    }
+
+    fn channel(discord: i64, failures: i32) -> db::Channel {
+        db::Channel {
+            discord,
+            failures,
+            kind: db::CHANNEL_TEXT,
+            webhook: None,
+            role: None,
+        }
+    }
+
+    fn count(report: &BroadcastReport, status: BroadcastResultType) -> u32 {
+        report.results.get(&status).copied().unwrap_or(0)
+    }
+
+    fn result(status: u16, headers: &[(&'static str, &str)], body: &str) -> BroadcastResultInner {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.insert(*name, HeaderValue::from_str(value).unwrap());
+        }
+        let inner = BroadcastResultInner::new(StatusCode::from_u16(status).unwrap(), &map);
+        inner.from(body.to_owned())
+    }
+
+    #[test]
+    fn classifies_responses() {
+        let sent = result(200, &[], r#"{"id": "123"}"#);
+        assert_eq!(sent.status, BroadcastResultType::Success);
+        assert_eq!(sent.message_id, Some(123));
+        assert_eq!(result(204, &[], "").status, BroadcastResultType::Success);
+
+        assert_eq!(result(400, &[], r#"{"code": 50035}"#).status, BroadcastResultType::InvalidFormBody);
+        assert_eq!(result(400, &[], r#"{"code": 40001}"#).status, BroadcastResultType::Unauthorized);
+        assert_eq!(result(400, &[], r#"{"code": 50083}"#).status, BroadcastResultType::ThreadArchived);
+        assert_eq!(result(401, &[], r#"{"code": 0}"#).status, BroadcastResultType::Unauthorized);
+        assert_eq!(result(403, &[], r#"{"code": 50013}"#).status, BroadcastResultType::MissingPermissions);
+        assert_eq!(result(403, &[], r#"{"code": 50001}"#).status, BroadcastResultType::MissingAccess);
+        assert_eq!(result(403, &[], r#"{"code": 50007}"#).status, BroadcastResultType::CannotMessageUser);
+        assert_eq!(result(403, &[], r#"{"message": "Forbidden"}"#).status, BroadcastResultType::Forbidden);
+        assert_eq!(result(404, &[], r#"{"code": 10003}"#).status, BroadcastResultType::UnknownChannel);
+        assert_eq!(result(404, &[], r#"{"code": 10015}"#).status, BroadcastResultType::UnknownWebhook);
+        assert_eq!(result(502, &[], r#"{}"#).status, BroadcastResultType::ServerError);
+    }
+
+    #[test]
+    fn classifies_rate_limits() {
+        let bucket = result(429, &[("X-RateLimit-Bucket", "abc"), ("Retry-After", "2")], r#"{"retry_after": 1.5, "global": false}"#);
+        assert_eq!(bucket.status, BroadcastResultType::RateLimited);
+        assert_eq!(bucket.rate_limit_retry, 1500);
+        assert_eq!(bucket.rate_limit_bucket.as_deref(), Some("abc"));
+
+        let global = result(429, &[], r#"{"retry_after": 0.5, "global": true}"#);
+        assert_eq!(global.status, BroadcastResultType::GlobalRateLimited);
+        assert_eq!(global.rate_limit_retry, 500);
+        assert_eq!(result(429, &[("X-RateLimit-Scope", "global")], r#"{"retry_after": 1}"#).status, BroadcastResultType::GlobalRateLimited);
+
+        let ban = result(429, &[], "<html>You are being rate limited</html>");
+        assert_eq!(ban.status, BroadcastResultType::CloudflareBan);
+        assert_eq!(ban.rate_limit_retry, CLOUDFLARE_BAN_WAIT);
+        assert_eq!(result(429, &[("Retry-After", "30")], "error code: 1015").rate_limit_retry, 30000);
+    }
+
+    #[test]
+    fn parses_webhook_urls() {
+        let token = "aB3_-xyz";
+        assert_eq!(parse_webhook_url("https://discord.com/api/webhooks/123/aB3_-xyz"), Some((123, token)));
+        assert_eq!(parse_webhook_url("https://discordapp.com/api/v10/webhooks/123/aB3_-xyz/"), Some((123, token)));
+        assert_eq!(parse_webhook_url("https://canary.discord.com/api/v9/webhooks/123/aB3_-xyz?wait=true"), Some((123, token)));
+
+        assert_eq!(parse_webhook_url("http://discord.com/api/webhooks/123/aB3_-xyz"), None);
+        assert_eq!(parse_webhook_url("https://example.com/api/webhooks/123/aB3_-xyz"), None);
+        assert_eq!(parse_webhook_url("https://discord.com.example.com/api/webhooks/123/aB3_-xyz"), None);
+        assert_eq!(parse_webhook_url("https://discord.com/api/webhooks/abc/aB3_-xyz"), None);
+        assert_eq!(parse_webhook_url("https://discord.com/api/webhooks/123/"), None);
+        assert_eq!(parse_webhook_url("https://discord.com/api/webhooks/123/aB3_-xyz/messages/1"), None);
+        assert_eq!(parse_webhook_url("https://discord.com/api/webhooks/123/a.b"), None);
+    }
+
+    #[tokio::test]
+    async fn rejects_other_webhook_hosts() {
+        let server = MockServer::start(|_, _, _| respond(200, "{}")).await;
+        let api = test_api(&server.base_url);
+        let url = server.base_url.clone() + "webhooks/123/token";
+        assert!(api.get_webhook(&url).await.is_err());
+        assert_eq!(server.total(), 0);
+    }
+
+    #[test]
+    fn matches_shop_filters() {
+        let items = vec![
+            ShopItem { name: "Renegade Raider".to_owned(), rarity: Some("Rare".to_owned()), set: None },
+            ShopItem { name: "Peely".to_owned(), rarity: Some("Epic".to_owned()), set: Some("Peely Bone".to_owned()) },
+        ];
+        let filter = |kind: &str, value: &str| db::ChannelFilter { kind: kind.to_owned(), value: value.to_owned() };
+
+        assert!(shop_matches(&items, &[]));
+        assert!(shop_matches(&items, &[filter(db::FILTER_RARITY, "rare")]));
+        assert!(shop_matches(&items, &[filter(db::FILTER_RARITY, "legendary"), filter(db::FILTER_SET, "PEELY BONE")]));
+        assert!(!shop_matches(&items, &[filter(db::FILTER_RARITY, "legendary")]));
+        assert!(!shop_matches(&items, &[filter("colour", "rare")]));
+        assert!(!shop_matches(&[], &[filter(db::FILTER_RARITY, "rare")]));
+    }
+
+    #[test]
+    fn mentions_role() {
+        let payload: JsonValue = serde_json::from_str(&with_mention(r#"{"content": "Shop @everyone"}"#, 42, false)).unwrap();
+        assert_eq!(payload["content"], "<@&42> Shop @everyone");
+        assert_eq!(payload["allowed_mentions"], json!({ "parse": [], "roles": ["42"] }));
+
+        let payload: JsonValue = serde_json::from_str(&with_mention(r#"{"embed": {"title": "Shop"}}"#, 42, false)).unwrap();
+        assert_eq!(payload["content"], "<@&42>");
+
+        let payload: JsonValue = serde_json::from_str(&with_mention(&thread_payload(r#"{"content": "Shop"}"#), 42, true)).unwrap();
+        assert_eq!(payload["name"], "Shop");
+        assert_eq!(payload["message"]["content"], "<@&42> Shop");
+        assert_eq!(payload["message"]["allowed_mentions"]["roles"], json!(["42"]));
+        assert!(payload.get("allowed_mentions").is_none());
+    }
+
+    #[test]
+    fn builds_thread_posts() {
+        let payload: JsonValue = serde_json::from_str(&thread_payload(r#"{"content": "Hello", "embed": {"title": "Item Shop"}}"#)).unwrap();
+        assert_eq!(payload["name"], "Item Shop");
+        assert_eq!(payload["message"]["content"], "Hello");
+        assert_eq!(payload["message"]["embeds"], json!([{ "title": "Item Shop" }]));
+        assert!(payload["message"].get("embed").is_none());
+
+        let long = "a".repeat(THREAD_NAME_MAX + 20);
+        let payload: JsonValue = serde_json::from_str(&thread_payload(&message_payload(&long))).unwrap();
+        assert_eq!(payload["name"].as_str().unwrap().len(), THREAD_NAME_MAX);
+
+        let payload: JsonValue = serde_json::from_str(&thread_payload(r#"{"content": ""}"#)).unwrap();
+        assert_eq!(payload["name"], "John Wick Bot");
+        assert_eq!(thread_payload("not json"), "");
+    }
+
+    #[test]
+    fn estimates_from_rate_limits() {
+        let api = test_api(DEFAULT_API_URL);
+        let mut channels: Vec<_> = (1..=100).map(|c| channel(c, 0)).collect();
+        assert_eq!(api.estimate_duration(&channels), ttime::Duration::from_secs(2));
+
+        channels[0].kind = db::CHANNEL_NEWS;
+        channels[1].kind = db::CHANNEL_WEBHOOK;
+        assert_eq!(api.estimate_duration(&channels), ttime::Duration::from_millis(2000));
+
+        let route = BroadcastAction::Send.route(db::CHANNEL_TEXT);
+        api.limiter().update(route, 3, &Some("bucket".to_owned()), 0, 60 * 1000);
+        assert!(api.estimate_duration(&channels) > ttime::Duration::from_secs(59));
+
+        api.limiter().pause_global(ttime::Duration::from_secs(120));
+        assert!(api.estimate_duration(&channels) > ttime::Duration::from_secs(121));
+    }
+
+    #[tokio::test]
+    async fn finishes_empty_broadcast() {
+        let api = test_api(DEFAULT_API_URL);
+        let log = test_log();
+        let mut broadcast = MessageBroadcast::new(log.clone(), 0, Vec::new(), api, &message_payload("Hello"), None);
+        match futures::poll!(&mut broadcast) {
+            Poll::Ready(report) => assert_eq!(report.results.len(), 0),
+            Poll::Pending => panic!("Nothing to send, should finish straight away"),
+        };
+        assert_eq!(log.recorded().finished, Some(false));
+    }
+
+    #[tokio::test]
+    async fn delivers_to_every_channel() {
+        let server = MockServer::start(|_, path, _| respond(200, &format!(r#"{{"id": "{}"}}"#, path.len()))).await;
+        let log = test_log();
+        let channels: Vec<_> = (1..=40).map(|c| channel(c, 0)).collect();
+        let mut webhook = channel(41, 0);
+        webhook.kind = db::CHANNEL_WEBHOOK;
+        webhook.webhook = Some("hook-token".to_owned());
+        let mut all = channels.clone();
+        all.push(webhook);
+
+        let broadcast = MessageBroadcast::new(log.clone(), 0, all, test_api(&server.base_url), &message_payload("Hello"), None);
+        let status = broadcast.status();
+        let report = broadcast.await;
+
+        assert_eq!(count(&report, BroadcastResultType::Success), 41);
+        assert_eq!(report.retries, 0);
+        assert_eq!(status.remaining.load(Ordering::Relaxed), 0);
+        assert_eq!(server.total(), 41);
+        for c in &channels {
+            assert_eq!(server.count("POST", &format!("channels/{}/messages", c.discord)), 1);
+        }
+        let recorded = log.recorded();
+        assert_eq!(recorded.deliveries.len(), 41);
+        assert!(recorded.deliveries.values().all(|s| matches!(s, db::DeliveryStatus::Delivered)));
+
+        let log = server.log.lock().unwrap();
+        let hook = log.requests.iter().find(|r| r.path == "webhooks/41/hook-token").unwrap();
+        assert!(!hook.authorized);
+        assert!(log.requests.iter().filter(|r| r.path.starts_with("channels/")).all(|r| r.authorized));
+        assert_eq!(serde_json::from_str::<JsonValue>(&hook.body).unwrap()["username"], WEBHOOK_USERNAME);
+    }
+
+    #[tokio::test]
+    async fn retries_rate_limited_requests() {
+        let server = MockServer::start(|_, path, seen| match (path, seen) {
+            ("channels/1/messages", 1) => with_header(respond(429, r#"{"retry_after": 0.1, "global": false}"#), "X-RateLimit-Bucket", "abc"),
+            ("channels/2/messages", 1) => respond(429, r#"{"retry_after": 0.1, "global": true}"#),
+            _ => respond(200, r#"{"id": "1"}"#),
+        }).await;
+        let channels = vec![channel(1, 0), channel(2, 0), channel(3, 0)];
+
+        let report = MessageBroadcast::new(test_log(), 0, channels, test_api(&server.base_url), &message_payload("Hello"), None).await;
+
+        assert_eq!(count(&report, BroadcastResultType::Success), 3);
+        assert_eq!(count(&report, BroadcastResultType::RateLimited), 1);
+        assert_eq!(count(&report, BroadcastResultType::GlobalRateLimited), 1);
+        assert_eq!(report.retries, 2);
+        assert_eq!(server.count("POST", "channels/1/messages"), 2);
+        assert_eq!(server.count("POST", "channels/2/messages"), 2);
+        assert_eq!(server.count("POST", "channels/3/messages"), 1);
+    }
+
+    #[tokio::test]
+    async fn retries_server_errors() {
+        let server = MockServer::start(|_, _, seen| match seen {
+            1 => respond(502, "{}"),
+            _ => respond(200, r#"{"id": "1"}"#),
+        }).await;
+        let log = test_log();
+
+        let report = MessageBroadcast::new(log.clone(), 0, vec![channel(1, 2)], test_api(&server.base_url), &message_payload("Hello"), None).await;
+
+        assert_eq!(count(&report, BroadcastResultType::ServerError), 1);
+        assert_eq!(count(&report, BroadcastResultType::Success), 1);
+        assert_eq!(report.retries, 1);
+        assert_eq!(report.failed, 0);
+        // A delivery clears the failures from earlier broadcasts.
+        assert_eq!(log.recorded().failures.get(&1), Some(&0));
+    }
+
+    #[tokio::test]
+    async fn unsubscribes_deleted_channels() {
+        let server = MockServer::start(|_, path, _| match path {
+            "channels/1/messages" => respond(404, r#"{"message": "Unknown Channel", "code": 10003}"#),
+            _ => respond(200, r#"{"id": "1"}"#),
+        }).await;
+        let log = test_log();
+
+        let channels = vec![channel(1, 0), channel(2, 0)];
+        let report = MessageBroadcast::new(log.clone(), 0, channels, test_api(&server.base_url), &message_payload("Hello"), None).await;
+
+        assert_eq!(count(&report, BroadcastResultType::UnknownChannel), 1);
+        assert_eq!(report.unsubscribed, 1);
+        assert_eq!(server.count("POST", "channels/1/messages"), 1);
+        let recorded = log.recorded();
+        assert_eq!(recorded.unsubscribed, vec![1]);
+        assert!(matches!(recorded.deliveries.get(&1), Some(db::DeliveryStatus::Unsubscribed)));
+        assert!(matches!(recorded.deliveries.get(&2), Some(db::DeliveryStatus::Delivered)));
+    }
+
+    #[tokio::test]
+    async fn unsubscribes_after_repeated_permission_errors() {
+        let server = MockServer::start(|_, _, _| respond(403, r#"{"message": "Missing Permissions", "code": 50013}"#)).await;
+        let log = test_log();
+
+        let channels = vec![channel(1, 0), channel(2, UNSUBSCRIBE_AFTER - 1)];
+        let report = MessageBroadcast::new(log.clone(), 0, channels, test_api(&server.base_url), &message_payload("Hello"), None).await;
+
+        assert_eq!(count(&report, BroadcastResultType::MissingPermissions), 2);
+        assert_eq!(report.unsubscribed, 1);
+        assert_eq!(report.retries, 0);
+        let recorded = log.recorded();
+        assert_eq!(recorded.failures.get(&1), Some(&1));
+        assert_eq!(recorded.unsubscribed, vec![2]);
+    }
+
+    #[tokio::test]
+    async fn looks_up_unknown_channels() {
+        let server = MockServer::start(|method, _, _| match method {
+            "GET" => respond(200, r#"{"id": "1", "type": 0}"#),
+            _ => respond(200, r#"{"id": "2"}"#),
+        }).await;
+        let log = test_log();
+        let mut unknown = channel(1, 0);
+        unknown.kind = db::CHANNEL_UNKNOWN;
+
+        let report = MessageBroadcast::new(log.clone(), 0, vec![unknown], test_api(&server.base_url), &message_payload("Hello"), None).await;
+
+        assert_eq!(count(&report, BroadcastResultType::Success), 1);
+        assert_eq!(server.count("GET", "channels/1"), 1);
+        assert_eq!(server.count("POST", "channels/1/messages"), 1);
+        assert_eq!(log.recorded().kinds.get(&1), Some(&db::CHANNEL_TEXT));
+    }
+
+    #[tokio::test]
+    async fn leaves_untracked_broadcasts_unrecorded() {
+        let server = MockServer::start(|_, _, _| respond(404, r#"{"message": "Unknown Channel", "code": 10003}"#)).await;
+        let log = test_log();
+
+        let report = MessageBroadcast::dry_run(log.clone(), vec![channel(1, 0)], test_api(&server.base_url), &message_payload("Hello"), 2).await;
+
+        assert_eq!(report.unsubscribed, 0);
+        assert_eq!(server.count("POST", "channels/2/messages"), 1);
+        let recorded = log.recorded();
+        assert!(recorded.unsubscribed.is_empty());
+        assert!(recorded.deliveries.is_empty());
+        assert_eq!(recorded.finished, None);
+    }
+
+    fn resident_memory() -> u64 {
+        let statm = std::fs::read_to_string("/proc/self/statm").unwrap_or_default();
+        statm.split_whitespace().nth(1).and_then(|p| p.parse::<u64>().ok()).unwrap_or(0) * 4096
+    }
+
+    // Sends to this many channels as fast as the mock answers. Returns how long it took, and the most memory
+    // the process grew by along the way.
+    async fn run_benchmark(db: &Arc<TestLog>, server: &MockServer, channels: i64) -> (ttime::Duration, u64) {
+        let before = resident_memory();
+        let content = Arc::new(BroadcastContent {
+            payload: message_payload("Hello"),
+            thread_payload: String::new(),
+            webhook_payload: String::new(),
+            attachment: None,
+            dry_run: None,
+        });
+        let requests = (1..=channels).map(|c| BroadcastInstance::new(BroadcastAction::Send, channel(c, 0), None)).collect();
+        let mut broadcast = MessageBroadcast::from_requests(db.clone(), 0, 0, test_api(&server.base_url), content, requests, "Benchmark".to_owned());
+        broadcast.tracked = false;
+
+        let started = ttime::Instant::now();
+        let mut most_memory = before;
+        // Reading the memory use is slow next to a poll, so it's only checked every so often.
+        let mut until_sample = 0;
+        let report = futures::future::poll_fn(|cx| {
+            let res = Pin::new(&mut broadcast).poll(cx);
+            if until_sample == 0 {
+                most_memory = cmp::max(most_memory, resident_memory());
+                until_sample = 1024;
+            }
+            until_sample -= 1;
+            res
+        }).await;
+
+        assert_eq!(count(&report, BroadcastResultType::Success), channels as u32);
+        (started.elapsed(), most_memory.saturating_sub(before))
+    }
+
+    // Run on its own, in release, so the timings and memory are the broadcast's:
+    // cargo test --release broadcast_benchmark -- --ignored --nocapture
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "benchmark"]
+    async fn broadcast_benchmark() {
+        let server = MockServer::unlogged(|_, _, _| respond(200, r#"{"id": "1"}"#)).await;
+        let db = test_log();
+
+        let (small, small_memory) = run_benchmark(&db, &server, 10_000).await;
+        let (large, large_memory) = run_benchmark(&db, &server, 100_000).await;
+        let small_per_channel = small / 10_000;
+        let large_per_channel = large / 100_000;
+        println!("10k channels: {:?} ({:?} each), {} KiB peak, {} bytes each", small, small_per_channel, small_memory / 1024, small_memory / 10_000);
+        println!("100k channels: {:?} ({:?} each), {} KiB peak, {} bytes each", large, large_per_channel, large_memory / 1024, large_memory / 100_000);
+
+        // Every channel sits in the queue as a db::Channel until it's sent, so memory grows with the channel count.
+        // Only REQUEST_COUNT of them are turned into requests at a time though, so each channel should stay small.
+        assert!(large_memory / 100_000 <= 1024, "{} bytes per channel at 100k", large_memory / 100_000);
+        // Ten times the channels should take about ten times as long.
+        assert!(large_per_channel <= small_per_channel * 2, "{:?} per channel at 100k, {:?} at 10k", large_per_channel, small_per_channel);
+    }
+}
@@ -61,6 +61,24 @@ impl MessageRequest {
     }
 }
 
+// Reply to an item_lookup request. The item is only set when the name matched exactly,
+// otherwise the server sends back the closest names it knows.
+#[derive(Deserialize)]
+pub struct ItemLookup {
+    pub item: Option<ItemInfo>,
+    #[serde(default)]
+    pub suggestions: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ItemInfo {
+    pub name: String,
+    pub rarity: Option<String>,
+    pub price: Option<u32>,
+    pub last_seen: Option<String>,
+    pub image: Option<String>,
+}
+
 pub struct ClientManager {
     sender: UnboundedSender<MessageRequest>,
     request_id: u32,
@@ -0,0 +1,123 @@
+use std::sync::{Arc, Mutex};
+use std::collections::VecDeque;
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::channel::oneshot;
+use futures::stream::StreamExt;
+use crate::broadcast::{MessageBroadcast, BroadcastReport, BroadcastStatus};
+
+struct QueuedBroadcast {
+    broadcast: MessageBroadcast,
+    report: oneshot::Sender<BroadcastReport>,
+}
+
+// Returned for every submitted broadcast, so the caller knows its job ID and can wait on the report.
+pub struct BroadcastHandle {
+    job_id: i32,
+    report: oneshot::Receiver<BroadcastReport>,
+}
+
+impl BroadcastHandle {
+    pub fn job_id(&self) -> i32 {
+        self.job_id
+    }
+
+    pub async fn report(self) -> Option<BroadcastReport> {
+        self.report.await.ok()
+    }
+}
+
+#[derive(Default)]
+struct CoordinatorState {
+    current: Option<Arc<BroadcastStatus>>,
+    queued: VecDeque<Arc<BroadcastStatus>>,
+}
+
+// Runs broadcasts one at a time, so they don't fight each other over the same rate limits.
+pub struct BroadcastCoordinator {
+    sender: UnboundedSender<QueuedBroadcast>,
+    state: Arc<Mutex<CoordinatorState>>,
+}
+
+impl BroadcastCoordinator {
+    pub fn start() -> Self {
+        let (sender, receiver) = unbounded::<QueuedBroadcast>();
+        let state = Arc::new(Mutex::new(CoordinatorState::default()));
+
+        let run_state = Arc::clone(&state);
+        tokio::spawn(async move {
+            run_loop(run_state, receiver).await;
+        });
+
+        Self {
+            sender,
+            state,
+        }
+    }
+
+    // Queues a broadcast behind any that are already running. The report is sent back once it finishes.
+    pub fn submit(&self, broadcast: MessageBroadcast) -> BroadcastHandle {
+        let (report_send, report_recv) = oneshot::channel::<BroadcastReport>();
+        let status = broadcast.status();
+        let mut lock = self.state.lock().unwrap();
+        lock.queued.push_back(Arc::clone(&status));
+        if let Err(e) = self.sender.unbounded_send(QueuedBroadcast { broadcast, report: report_send }) {
+            println!("Could not queue broadcast: {}", e);
+            lock.queued.pop_back();
+        }
+
+        BroadcastHandle {
+            job_id: status.job_id(),
+            report: report_recv,
+        }
+    }
+
+    // Cancels the running or queued broadcasts for a job. Queued ones finish straight away once they're reached.
+    pub fn cancel(&self, job_id: i32) -> bool {
+        let lock = self.state.lock().unwrap();
+        let mut found = false;
+        for status in lock.current.iter().chain(lock.queued.iter()) {
+            if status.job_id() == job_id {
+                status.cancel();
+                found = true;
+            }
+        }
+
+        found
+    }
+
+    pub fn status(&self) -> String {
+        let lock = self.state.lock().unwrap();
+        let mut status = match &lock.current {
+            Some(current) => format!("Running {}", current),
+            None => "No broadcast running".to_owned(),
+        };
+        for queued in lock.queued.iter() {
+            status += &format!("\nQueued {}", queued);
+        }
+
+        status
+    }
+}
+
+async fn run_loop(state: Arc<Mutex<CoordinatorState>>, mut receiver: UnboundedReceiver<QueuedBroadcast>) {
+    while let Some(job) = receiver.next().await {
+        {
+            let mut lock = state.lock().unwrap();
+            lock.current = lock.queued.pop_front();
+        }
+
+        // Each broadcast gets its own task, so one that panics doesn't take the coordinator down with it.
+        let job_id = job.broadcast.status().job_id();
+        let result = tokio::spawn(job.broadcast).await;
+
+        state.lock().unwrap().current = None;
+        match result {
+            Ok(report) => {
+                if job.report.send(report).is_err() {
+                    println!("Broadcast finished with nobody waiting on the report");
+                }
+            },
+            Err(e) => println!("Broadcast {} stopped unexpectedly: {}", job_id, e),
+        };
+    }
+}
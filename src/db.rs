@@ -1,4 +1,5 @@
-use tokio_postgres::{NoTls, Error as DBError, Client};
+use std::collections::HashMap;
+use tokio_postgres::{NoTls, Error as DBError, Client, Row};
 
 #[derive(Debug)]
 pub struct DBErr;
@@ -20,6 +21,78 @@ impl std::error::Error for DBErr {}
 
 type DBResult<T> = Result<T, DBErr>;
 
+// Discord's channel types, only the ones broadcasts care about.
+pub const CHANNEL_TEXT: i16 = 0;
+// Used for direct messages to a user, the "channel" is the user's ID.
+pub const CHANNEL_DM: i16 = 1;
+pub const CHANNEL_NEWS: i16 = 5;
+pub const CHANNEL_NEWS_THREAD: i16 = 10;
+pub const CHANNEL_PUBLIC_THREAD: i16 = 11;
+pub const CHANNEL_PRIVATE_THREAD: i16 = 12;
+pub const CHANNEL_FORUM: i16 = 15;
+// What a channel can subscribe to. Broadcast hooks each send to one of these.
+pub const TOPIC_SHOP: &str = "shop";
+pub const TOPIC_ANNOUNCEMENTS: &str = "announcements";
+pub const TOPICS: &[&str] = &[TOPIC_SHOP, TOPIC_ANNOUNCEMENTS];
+
+// Not a Discord channel type. The subscription is a webhook, keyed by the webhook's ID.
+pub const CHANNEL_WEBHOOK: i16 = -1;
+// Subscribed before the channel type was stored. It's looked up the next time the channel is broadcast to.
+pub const CHANNEL_UNKNOWN: i16 = -2;
+
+#[derive(Debug, Clone)]
+pub struct Channel {
+    pub discord: i64,
+    // Broadcasts in a row that failed on permissions.
+    pub failures: i32,
+    pub kind: i16,
+    // Token for webhook subscriptions. The execute URL is built from it when sending.
+    pub webhook: Option<String>,
+    // Pinged on every broadcast to the channel.
+    pub role: Option<i64>,
+}
+
+impl Channel {
+    fn from_row(row: &Row) -> Self {
+        Self {
+            discord: row.get("discord"),
+            failures: row.get("failures"),
+            kind: row.get::<_, Option<i16>>("kind").unwrap_or(CHANNEL_UNKNOWN),
+            webhook: row.get("webhook"),
+            role: row.get("role"),
+        }
+    }
+}
+
+// Limits a channel's shop posts to days with a matching item.
+#[derive(Debug, Clone)]
+pub struct ChannelFilter {
+    pub kind: String,
+    pub value: String,
+}
+
+pub const FILTER_RARITY: &str = "rarity";
+pub const FILTER_SET: &str = "set";
+
+#[derive(Debug, Clone, Copy)]
+pub enum DeliveryStatus {
+    Delivered,
+    Failed,
+    Unsubscribed,
+    Retracted,
+}
+
+impl DeliveryStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            DeliveryStatus::Delivered => "delivered",
+            DeliveryStatus::Failed => "failed",
+            DeliveryStatus::Unsubscribed => "unsubscribed",
+            DeliveryStatus::Retracted => "retracted",
+        }
+    }
+}
+
 pub struct DBManager {
     client: Client,
 }
@@ -35,9 +108,65 @@ impl DBManager {
             }
         });
         
-        Ok(Self {
+        let manager = Self {
             client,
-        })
+        };
+        manager.create_tables().await?;
+
+        Ok(manager)
+    }
+
+    // Runs as one transaction, and the lock makes connections take turns, so two starting at once don't trip over each other.
+    async fn create_tables(&self) -> DBResult<()> {
+        self.client.batch_execute("
+            SELECT pg_advisory_xact_lock(2718281828);
+            CREATE TABLE IF NOT EXISTS channels (
+                discord BIGINT PRIMARY KEY
+            );
+            CREATE TABLE IF NOT EXISTS broadcast_jobs (
+                id SERIAL PRIMARY KEY,
+                payload TEXT NOT NULL,
+                created TIMESTAMPTZ NOT NULL DEFAULT now(),
+                finished BOOLEAN NOT NULL DEFAULT FALSE
+            );
+            CREATE TABLE IF NOT EXISTS broadcast_deliveries (
+                job_id INTEGER NOT NULL REFERENCES broadcast_jobs (id) ON DELETE CASCADE,
+                discord BIGINT NOT NULL,
+                status TEXT NOT NULL DEFAULT 'pending',
+                PRIMARY KEY (job_id, discord)
+            );
+            ALTER TABLE broadcast_jobs ADD COLUMN IF NOT EXISTS attachment TEXT;
+            ALTER TABLE channels ADD COLUMN IF NOT EXISTS failures INTEGER NOT NULL DEFAULT 0;
+            ALTER TABLE broadcast_deliveries ADD COLUMN IF NOT EXISTS message_id BIGINT;
+            ALTER TABLE channels ADD COLUMN IF NOT EXISTS kind SMALLINT;
+            ALTER TABLE broadcast_jobs ADD COLUMN IF NOT EXISTS idempotency_key TEXT;
+            ALTER TABLE channels ADD COLUMN IF NOT EXISTS webhook TEXT;
+            ALTER TABLE channels ADD COLUMN IF NOT EXISTS role BIGINT;
+            ALTER TABLE channels ADD COLUMN IF NOT EXISTS topics TEXT[];
+            CREATE TABLE IF NOT EXISTS wishlist (
+                user_id BIGINT NOT NULL,
+                item TEXT NOT NULL,
+                PRIMARY KEY (user_id, item)
+            );
+            CREATE TABLE IF NOT EXISTS channel_filters (
+                discord BIGINT NOT NULL,
+                kind TEXT NOT NULL,
+                value TEXT NOT NULL,
+                PRIMARY KEY (discord, kind, value)
+            );
+            CREATE TABLE IF NOT EXISTS broadcast_keys (
+                idempotency_key TEXT PRIMARY KEY,
+                created TIMESTAMPTZ NOT NULL DEFAULT now()
+            );
+            CREATE TABLE IF NOT EXISTS scheduled_broadcasts (
+                id SERIAL PRIMARY KEY,
+                payload TEXT NOT NULL,
+                run_at TIMESTAMPTZ NOT NULL,
+                author BIGINT NOT NULL
+            );
+        ").await?;
+
+        Ok(())
     }
 
     pub async fn channel_exists(&self, channel_id: i64) -> DBResult<bool> {
@@ -54,20 +183,224 @@ impl DBManager {
         }
     }
 
-    pub async fn get_channels(&self) -> DBResult<Vec<i64>> {
-        let rows = self.client.query("SELECT discord FROM channels", &[]).await?;
-        Ok(rows.into_iter().map(|v| v.get(0)).collect())
+    // Channels without any topics set get everything.
+    pub async fn get_channels(&self, topic: &str) -> DBResult<Vec<Channel>> {
+        let rows = self.client.query("SELECT discord, failures, kind, webhook, role FROM channels WHERE topics IS NULL OR $1 = ANY(topics)", &[&topic]).await?;
+        Ok(rows.iter().map(Channel::from_row).collect())
     }
 
-    pub async fn insert_channel(&self, channel_id: i64) -> DBResult<()> {
-        self.client.execute("INSERT INTO channels (discord) VALUES($1) ON CONFLICT DO NOTHING", &[&channel_id]).await?;
+    pub async fn insert_channel(&self, channel_id: i64, kind: i16, role: Option<i64>, topic: Option<&str>) -> DBResult<()> {
+        let topics = topic.map(|t| vec![t]);
+        self.client.execute("INSERT INTO channels (discord, kind, role, topics) VALUES($1, $2, $3, $4) ON CONFLICT DO NOTHING", &[&channel_id, &kind, &role, &topics]).await?;
+
+        Ok(())
+    }
+
+    // A channel that already gets everything is left alone.
+    pub async fn add_channel_topic(&self, channel_id: i64, topic: &str) -> DBResult<()> {
+        self.client.execute("UPDATE channels SET topics = array_append(topics, $2) WHERE discord = $1 AND topics IS NOT NULL AND NOT ($2 = ANY(topics))", &[&channel_id, &topic]).await?;
+
+        Ok(())
+    }
+
+    pub async fn clear_channel_topics(&self, channel_id: i64) -> DBResult<()> {
+        self.client.execute("UPDATE channels SET topics = NULL WHERE discord = $1", &[&channel_id]).await?;
+
+        Ok(())
+    }
+
+    // Channels left without any topics are unsubscribed, and lose their filters like any other unsubscribe.
+    pub async fn remove_channel_topic(&self, channel_id: i64, topic: &str) -> DBResult<()> {
+        self.client.execute("UPDATE channels SET topics = array_remove(COALESCE(topics, $3), $2) WHERE discord = $1", &[&channel_id, &topic, &TOPICS]).await?;
+        let removed = self.client.execute("DELETE FROM channels WHERE discord = $1 AND cardinality(topics) = 0", &[&channel_id]).await?;
+        if removed > 0 {
+            self.client.execute("DELETE FROM channel_filters WHERE discord = $1", &[&channel_id]).await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn set_channel_role(&self, channel_id: i64, role: Option<i64>) -> DBResult<()> {
+        self.client.execute("UPDATE channels SET role = $2 WHERE discord = $1", &[&channel_id, &role]).await?;
+
+        Ok(())
+    }
+
+    pub async fn insert_webhook(&self, webhook_id: i64, token: &str, topic: Option<&str>) -> DBResult<()> {
+        let topics = topic.map(|t| vec![t]);
+        self.client.execute("INSERT INTO channels (discord, kind, webhook, topics) VALUES($1, $2, $3, $4) ON CONFLICT DO NOTHING", &[&webhook_id, &CHANNEL_WEBHOOK, &token, &topics]).await?;
+
+        Ok(())
+    }
+
+    pub async fn set_channel_kind(&self, channel_id: i64, kind: i16) -> DBResult<()> {
+        self.client.execute("UPDATE channels SET kind = $2 WHERE discord = $1", &[&channel_id, &kind]).await?;
 
         Ok(())
     }
 
     pub async fn delete_channel(&self, channel_id: i64) -> DBResult<()> {
         self.client.execute("DELETE FROM channels WHERE discord = $1", &[&channel_id]).await?;
+        self.client.execute("DELETE FROM channel_filters WHERE discord = $1", &[&channel_id]).await?;
+
+        Ok(())
+    }
+
+    // Items are stored lowercase, so matching doesn't care how they were typed.
+    pub async fn add_wish(&self, user_id: i64, item: &str) -> DBResult<()> {
+        self.client.execute("INSERT INTO wishlist (user_id, item) VALUES($1, lower($2)) ON CONFLICT DO NOTHING", &[&user_id, &item]).await?;
+
+        Ok(())
+    }
+
+    pub async fn remove_wish(&self, user_id: i64, item: &str) -> DBResult<bool> {
+        let count = self.client.execute("DELETE FROM wishlist WHERE user_id = $1 AND item = lower($2)", &[&user_id, &item]).await?;
+
+        Ok(count > 0)
+    }
+
+    pub async fn get_wishes(&self, user_id: i64) -> DBResult<Vec<String>> {
+        let rows = self.client.query("SELECT item FROM wishlist WHERE user_id = $1 ORDER BY item", &[&user_id]).await?;
+        Ok(rows.into_iter().map(|v| v.get(0)).collect())
+    }
+
+    // Every user wishing for one of the items, with the item they wished for.
+    pub async fn get_wish_matches(&self, items: &[String]) -> DBResult<Vec<(i64, String)>> {
+        let lowered: Vec<String> = items.iter().map(|i| i.to_lowercase()).collect();
+        let rows = self.client.query("SELECT user_id, item FROM wishlist WHERE item = ANY($1) ORDER BY user_id", &[&lowered]).await?;
+        Ok(rows.into_iter().map(|v| (v.get(0), v.get(1))).collect())
+    }
+
+    pub async fn add_channel_filter(&self, channel_id: i64, kind: &str, value: &str) -> DBResult<()> {
+        self.client.execute("INSERT INTO channel_filters (discord, kind, value) VALUES($1, $2, $3) ON CONFLICT DO NOTHING", &[&channel_id, &kind, &value]).await?;
+
+        Ok(())
+    }
+
+    pub async fn clear_channel_filters(&self, channel_id: i64) -> DBResult<()> {
+        self.client.execute("DELETE FROM channel_filters WHERE discord = $1", &[&channel_id]).await?;
+
+        Ok(())
+    }
+
+    pub async fn get_channel_filters(&self, channel_id: i64) -> DBResult<Vec<ChannelFilter>> {
+        let rows = self.client.query("SELECT kind, value FROM channel_filters WHERE discord = $1 ORDER BY kind, value", &[&channel_id]).await?;
+        Ok(rows.into_iter().map(|v| ChannelFilter { kind: v.get(0), value: v.get(1) }).collect())
+    }
+
+    // Every channel's filters at once, for working out who gets today's shop.
+    pub async fn get_all_filters(&self) -> DBResult<HashMap<i64, Vec<ChannelFilter>>> {
+        let rows = self.client.query("SELECT discord, kind, value FROM channel_filters", &[]).await?;
+        let mut filters: HashMap<i64, Vec<ChannelFilter>> = HashMap::new();
+        for row in rows {
+            filters.entry(row.get(0)).or_default().push(ChannelFilter { kind: row.get(1), value: row.get(2) });
+        }
+
+        Ok(filters)
+    }
+
+    pub async fn add_channel_failure(&self, channel_id: i64) -> DBResult<()> {
+        self.client.execute("UPDATE channels SET failures = failures + 1 WHERE discord = $1", &[&channel_id]).await?;
 
         Ok(())
     }
-}
\ No newline at end of file
+
+    pub async fn reset_channel_failures(&self, channel_id: i64) -> DBResult<()> {
+        self.client.execute("UPDATE channels SET failures = 0 WHERE discord = $1", &[&channel_id]).await?;
+
+        Ok(())
+    }
+
+    // The channels are stored with the job, so a resumed job goes to the same channels.
+    // Returns None when a job with the same key was created within the window, and nothing is inserted.
+    // The key is claimed with an upsert, so when two jobs with the same key race only one of them gets it.
+    // The scheduled broadcast it came from, if any, is removed along with storing its job, or finding it already ran.
+    pub async fn create_broadcast_job(&self, payload: &str, attachment: Option<&str>, key: Option<&str>, window_secs: f64, channels: &[i64], schedule: Option<i32>) -> DBResult<Option<i32>> {
+        let rows = self.client.query("
+            WITH claim AS (
+                INSERT INTO broadcast_keys (idempotency_key) SELECT $3::TEXT WHERE $3::TEXT IS NOT NULL
+                ON CONFLICT (idempotency_key) DO UPDATE SET created = now()
+                WHERE broadcast_keys.created <= now() - make_interval(secs => $4)
+                RETURNING idempotency_key
+            ),
+            job AS (
+                INSERT INTO broadcast_jobs (payload, attachment, idempotency_key) SELECT $1, $2, $3
+                WHERE $3::TEXT IS NULL OR EXISTS (SELECT 1 FROM claim)
+                RETURNING id
+            ),
+            deliveries AS (INSERT INTO broadcast_deliveries (job_id, discord) SELECT job.id, c FROM job, unnest($5::BIGINT[]) AS c),
+            scheduled AS (DELETE FROM scheduled_broadcasts WHERE id = $6)
+            SELECT id FROM job", &[&payload, &attachment, &key, &window_secs, &channels, &schedule]).await?;
+
+        Ok(rows.first().map(|r| r.get(0)))
+    }
+
+    pub async fn get_unfinished_jobs(&self) -> DBResult<Vec<(i32, String, Option<String>)>> {
+        let rows = self.client.query("SELECT id, payload, attachment FROM broadcast_jobs WHERE finished = FALSE ORDER BY id", &[]).await?;
+        Ok(rows.into_iter().map(|v| (v.get(0), v.get(1), v.get(2))).collect())
+    }
+
+    // Channels that unsubscribed since the job started are left out.
+    pub async fn get_pending_deliveries(&self, job_id: i32) -> DBResult<Vec<Channel>> {
+        let rows = self.client.query("SELECT c.discord, c.failures, c.kind, c.webhook, c.role FROM broadcast_deliveries d INNER JOIN channels c ON c.discord = d.discord WHERE d.job_id = $1 AND d.status = 'pending'", &[&job_id]).await?;
+        Ok(rows.iter().map(Channel::from_row).collect())
+    }
+
+    pub async fn set_delivery_status(&self, job_id: i32, channel_id: i64, status: DeliveryStatus, message_id: Option<i64>) -> DBResult<()> {
+        self.client.execute("UPDATE broadcast_deliveries SET status = $3, message_id = COALESCE($4, message_id) WHERE job_id = $1 AND discord = $2", &[&job_id, &channel_id, &status.as_str(), &message_id]).await?;
+
+        Ok(())
+    }
+
+    // Messages a job has posted, including channels that have since unsubscribed.
+    pub async fn get_delivered_messages(&self, job_id: i32) -> DBResult<Vec<(Channel, i64)>> {
+        let rows = self.client.query("SELECT d.discord, COALESCE(c.failures, 0) AS failures, c.kind, c.webhook, c.role, d.message_id FROM broadcast_deliveries d LEFT JOIN channels c ON c.discord = d.discord WHERE d.job_id = $1 AND d.status = 'delivered' AND d.message_id IS NOT NULL", &[&job_id]).await?;
+        Ok(rows.iter().map(|v| (Channel::from_row(v), v.get("message_id"))).collect())
+    }
+
+    pub async fn finish_broadcast_job(&self, job_id: i32) -> DBResult<()> {
+        self.client.execute("UPDATE broadcast_jobs SET finished = TRUE WHERE id = $1", &[&job_id]).await?;
+
+        Ok(())
+    }
+
+    // The key a scheduled broadcast's job is stored with, so it only runs once even if the scheduler picks it up again
+    // before the job is stored.
+    pub fn scheduled_key(id: i32) -> String {
+        format!("scheduled:{}", id)
+    }
+
+    // Times are passed around as unix timestamps.
+    pub async fn create_scheduled_broadcast(&self, payload: &str, run_at: i64, author: i64) -> DBResult<i32> {
+        let rows = self.client.query("INSERT INTO scheduled_broadcasts (payload, run_at, author) VALUES ($1, to_timestamp($2), $3) RETURNING id", &[&payload, &(run_at as f64), &author]).await?;
+        let row = match rows.first() {
+            Some(r) => r,
+            None => return Err(DBErr),
+        };
+
+        Ok(row.get(0))
+    }
+
+    pub async fn get_scheduled_broadcasts(&self) -> DBResult<Vec<(i32, String, i64)>> {
+        let rows = self.client.query("SELECT id, payload, EXTRACT(EPOCH FROM run_at)::BIGINT FROM scheduled_broadcasts ORDER BY run_at", &[]).await?;
+        Ok(rows.into_iter().map(|v| (v.get(0), v.get(1), v.get(2))).collect())
+    }
+
+    pub async fn delete_scheduled_broadcast(&self, id: i32) -> DBResult<bool> {
+        let count = self.client.execute("DELETE FROM scheduled_broadcasts WHERE id = $1", &[&id]).await?;
+
+        Ok(count > 0)
+    }
+
+    // Due broadcasts stay put until their job is stored, so one that fails to start is tried again.
+    pub async fn get_due_broadcasts(&self) -> DBResult<Vec<(i32, String, i64)>> {
+        let rows = self.client.query("SELECT id, payload, author FROM scheduled_broadcasts WHERE run_at <= now() ORDER BY run_at", &[]).await?;
+        Ok(rows.into_iter().map(|v| (v.get(0), v.get(1), v.get(2))).collect())
+    }
+
+    // Finishes a job early. Deliveries that never went out are marked so they aren't mistaken for pending ones.
+    pub async fn cancel_broadcast_job(&self, job_id: i32) -> DBResult<()> {
+        self.client.execute("UPDATE broadcast_deliveries SET status = 'cancelled' WHERE job_id = $1 AND status = 'pending'", &[&job_id]).await?;
+        self.finish_broadcast_job(job_id).await
+    }
+}
@@ -4,7 +4,8 @@ use std::error::Error;
 use chrono::prelude::*;
 use serenity::{
     async_trait,
-    model::{channel::Message, gateway::Ready, permissions::Permissions, id::ChannelId},
+    model::{channel::Message, gateway::Ready, permissions::Permissions, id::{ChannelId, UserId}},
+    http::Http,
     prelude::*,
 };
 
@@ -12,13 +13,15 @@ mod db;
 mod broadcast;
 mod shutdown;
 mod client;
+mod ratelimit;
+mod coordinator;
 
 type BoxedError = Box<dyn Error + Send + Sync>;
 type JWResult<T> = Result<T, BoxedError>;
 
-struct BotToken {}
-impl TypeMapKey for BotToken {
-    type Value = String;
+struct DiscordApi {}
+impl TypeMapKey for DiscordApi {
+    type Value = Arc<broadcast::DiscordApi>;
 }
 
 struct HttpClient {}
@@ -31,6 +34,19 @@ impl TypeMapKey for DBManager {
     type Value = Arc<db::DBManager>;
 }
 
+struct Coordinator {}
+impl TypeMapKey for Coordinator {
+    type Value = Arc<coordinator::BroadcastCoordinator>;
+}
+
+// How long, in seconds, a broadcast key blocks the same broadcast from going out again.
+struct DedupWindow {}
+impl TypeMapKey for DedupWindow {
+    type Value = u64;
+}
+
+const DEFAULT_DEDUP_WINDOW: u64 = 12 * 60 * 60;
+
 impl TypeMapKey for client::ClientManager {
     type Value = Arc<SMutex<client::ClientManager>>;
 }
@@ -58,6 +74,27 @@ impl Handler {
         Ok(channel.say(&ctx.http, message).await?)
     }
 
+    // For replies that repeat what someone typed, so they can't get the bot to ping @everyone or a role.
+    async fn send_quiet(&self, ctx: &Context, channel: ChannelId, message: &str) -> JWResult<Message> {
+        Ok(channel.send_message(&ctx.http, |m| m.content(message).allowed_mentions(|a| a.empty_parse())).await?)
+    }
+
+    // Stored with the subscription, so broadcasts know which endpoint to use for the channel.
+    // Fails if the channel is in another guild, so nobody can subscribe channels they don't manage.
+    async fn get_channel_kind(&self, ctx: &Context, msg: &Message, channel: ChannelId) -> JWResult<i16> {
+        let api = {
+            let lock = ctx.data.read().await;
+            Arc::clone(lock.get::<DiscordApi>().unwrap())
+        };
+
+        let info = api.get_channel(channel.0).await?;
+        let guild = msg.guild_id.map(|g| g.0.to_string());
+        if info.guild_id.is_none() || info.guild_id != guild {
+            return Err(format!("Channel {} is not in guild {:?}", channel.0, guild).into());
+        }
+        Ok(info.kind)
+    }
+
     async fn subscribe_channel(&self, ctx: &Context, msg: &Message) -> JWResult<()> {
         let permissions = self.get_permissions_user(&ctx, &msg).await?;
         if !permissions.contains(Permissions::MANAGE_CHANNELS) {
@@ -70,20 +107,268 @@ impl Handler {
             Arc::clone(lock.get::<DBManager>().unwrap())
         };
 
-        if db.channel_exists(msg.channel_id.0 as i64).await? == true {
-            self.send_message(&ctx, msg.channel_id, "This channel is already subscribed.").await?;
+        // Forum channels can't be typed in, so they're subscribed by mentioning them from somewhere else.
+        let mentioned = msg.content[10..].split_whitespace().filter_map(parse_channel_mention).next();
+        let channel = mentioned.unwrap_or(msg.channel_id);
+        // Discord only lists roles from the same guild as mentioned.
+        let role = msg.mention_roles.first().map(|r| r.0 as i64);
+        let topic = match parse_topic(&msg.content[10..]) {
+            Ok(t) => t,
+            Err(reply) => {
+                self.send_quiet(&ctx, msg.channel_id, &reply).await?;
+                return Ok(());
+            },
+        };
+        let kind = match self.get_channel_kind(&ctx, &msg, channel).await {
+            Ok(k) => k,
+            Err(why) => {
+                println!("Could not look up channel {}: {}", channel.0, why);
+                msg.author.direct_message(ctx, |m| m.content(SUBSCRIBE_FAILED)).await?;
+                return Ok(());
+            },
+        };
+        match kind {
+            db::CHANNEL_TEXT | db::CHANNEL_NEWS | db::CHANNEL_NEWS_THREAD | db::CHANNEL_PUBLIC_THREAD | db::CHANNEL_PRIVATE_THREAD | db::CHANNEL_FORUM => (),
+            _ => {
+                self.send_message(&ctx, msg.channel_id, "I can only post in text, announcement and forum channels, or threads.").await?;
+                return Ok(());
+            },
+        };
+        if db.channel_exists(channel.0 as i64).await? == true {
+            // Older subscriptions were stored without their channel type.
+            db.set_channel_kind(channel.0 as i64, kind).await?;
+            // Subscribing again with a role mentioned changes the role, !unsubscribe ping removes it.
+            if role.is_some() {
+                db.set_channel_role(channel.0 as i64, role).await?;
+            }
+            match topic {
+                Some(TOPIC_ALL) => db.clear_channel_topics(channel.0 as i64).await?,
+                Some(t) => db.add_channel_topic(channel.0 as i64, t).await?,
+                None => (),
+            };
+            let mut reply = match topic {
+                Some(TOPIC_ALL) => "This channel is already subscribed, it'll get everything from now on.".to_owned(),
+                Some(t) => format!("This channel is already subscribed, it'll get {} too.", t),
+                None => "This channel is already subscribed.".to_owned(),
+            };
+            if let Some(r) = role {
+                reply += &format!(" I'll ping <@&{}> from now on.", r);
+            }
+            self.send_message(&ctx, msg.channel_id, &reply).await?;
             return Ok(());
         }
-        match msg.channel_id.say(&ctx.http, "Thanks! I'll let you know in this channel.").await {
-            Ok(_res) => db.insert_channel(msg.channel_id.0 as i64).await?,
+        let reply = match mentioned {
+            Some(c) => msg.channel_id.say(&ctx.http, format!("Thanks! I'll let you know in <#{}>.", c.0)).await,
+            None => msg.channel_id.say(&ctx.http, "Thanks! I'll let you know in this channel.").await,
+        };
+        match reply {
+            Ok(_res) => db.insert_channel(channel.0 as i64, kind, role, topic.filter(|t| *t != TOPIC_ALL)).await?,
             Err(why) => {
                 println!("Could not send message to channel {}: {}", msg.channel_id.0, why);
-                msg.author.direct_message(ctx, |m| m.content("I was not able to subscribe to that channel. I may not have permissions to do so.")).await?;
+                msg.author.direct_message(ctx, |m| m.content(SUBSCRIBE_FAILED)).await?;
             }
         };
         Ok(())
     }
 
+    // !subscribe webhook <url>, for guilds that would rather not let the bot post itself.
+    async fn subscribe_webhook(&self, ctx: &Context, msg: &Message) -> JWResult<()> {
+        let permissions = self.get_permissions_user(&ctx, &msg).await?;
+        if !permissions.contains(Permissions::MANAGE_WEBHOOKS) {
+            self.send_message(&ctx, msg.channel_id, "You do not have the server permissions required to do this.").await?;
+            return Ok(());
+        }
+
+        let (db, api) = {
+            let lock = ctx.data.read().await;
+            (Arc::clone(lock.get::<DBManager>().unwrap()), Arc::clone(lock.get::<DiscordApi>().unwrap()))
+        };
+
+        // The URL is as good as a password, so don't leave it sitting in the channel.
+        if let Err(e) = msg.delete(ctx).await {
+            println!("Could not delete webhook message in {}: {}", msg.channel_id.0, e);
+        }
+
+        let mut args = msg.content[19..].split_whitespace();
+        let url = args.next().unwrap_or("");
+        let topic = match parse_topic(&args.collect::<Vec<_>>().join(" ")) {
+            Ok(t) => t.filter(|t| *t != TOPIC_ALL),
+            Err(reply) => {
+                self.send_quiet(&ctx, msg.channel_id, &reply).await?;
+                return Ok(());
+            },
+        };
+        let webhook = match api.get_webhook(url).await {
+            Ok(w) => w,
+            Err(e) => {
+                println!("Webhook Error: {}", e);
+                self.send_message(&ctx, msg.channel_id, "That doesn't look like a working webhook URL.").await?;
+                return Ok(());
+            },
+        };
+        if webhook.guild_id != msg.guild_id.map(|g| g.0.to_string()) {
+            self.send_message(&ctx, msg.channel_id, "That webhook belongs to another server.").await?;
+            return Ok(());
+        }
+
+        db.insert_webhook(webhook.id as i64, &webhook.token, topic).await?;
+        self.send_message(&ctx, msg.channel_id, "Thanks! I'll post through that webhook.").await?;
+        Ok(())
+    }
+
+    async fn unsubscribe_webhook(&self, ctx: &Context, msg: &Message) -> JWResult<()> {
+        let permissions = self.get_permissions_user(&ctx, &msg).await?;
+        if !permissions.contains(Permissions::MANAGE_WEBHOOKS) {
+            self.send_message(&ctx, msg.channel_id, "You do not have the server permissions required to do this.").await?;
+            return Ok(());
+        }
+
+        let (db, api) = {
+            let lock = ctx.data.read().await;
+            (Arc::clone(lock.get::<DBManager>().unwrap()), Arc::clone(lock.get::<DiscordApi>().unwrap()))
+        };
+
+        if let Err(e) = msg.delete(ctx).await {
+            println!("Could not delete webhook message in {}: {}", msg.channel_id.0, e);
+        }
+
+        let webhook = api.get_webhook(msg.content[21..].trim()).await?;
+        if webhook.guild_id != msg.guild_id.map(|g| g.0.to_string()) {
+            self.send_message(&ctx, msg.channel_id, "That webhook belongs to another server.").await?;
+            return Ok(());
+        }
+
+        db.delete_channel(webhook.id as i64).await?;
+        self.send_message(&ctx, msg.channel_id, "I'll stop posting through that webhook.").await?;
+        Ok(())
+    }
+
+    // !filter rarity <rarity>, !filter set <set>, !filter list and !filter clear
+    async fn filter_channel(&self, ctx: &Context, msg: &Message) -> JWResult<()> {
+        let permissions = self.get_permissions_user(&ctx, &msg).await?;
+        if !permissions.contains(Permissions::MANAGE_CHANNELS) {
+            self.send_message(&ctx, msg.channel_id, "You do not have the server permissions required to do this.").await?;
+            return Ok(());
+        }
+
+        let db = {
+            let lock = ctx.data.read().await;
+            Arc::clone(lock.get::<DBManager>().unwrap())
+        };
+
+        let channel_id = msg.channel_id.0 as i64;
+        if !db.channel_exists(channel_id).await? {
+            self.send_message(&ctx, msg.channel_id, "This channel isn't subscribed, use !subscribe first.").await?;
+            return Ok(());
+        }
+
+        let mut args = msg.content[8..].splitn(2, ' ');
+        let kind = args.next().unwrap_or("");
+        let value = args.next().unwrap_or("").trim().trim_matches('"');
+        let reply = match (kind, value) {
+            ("list", _) => {
+                let filters = db.get_channel_filters(channel_id).await?;
+                match filters.len() {
+                    0 => "No filters, this channel gets every shop.".to_owned(),
+                    _ => filters.iter().fold("Only posting shops with:".to_owned(), |r, f| r + &format!("\n{}: {}", f.kind, f.value)),
+                }
+            },
+            ("clear", _) => {
+                db.clear_channel_filters(channel_id).await?;
+                "Filters cleared, this channel gets every shop.".to_owned()
+            },
+            (db::FILTER_RARITY, v) | (db::FILTER_SET, v) if !v.is_empty() => {
+                db.add_channel_filter(channel_id, kind, v).await?;
+                format!("I'll only post shops here with a {} of {}, or matching your other filters.", kind, v)
+            },
+            _ => "Usage: !filter rarity <rarity>, !filter set \"<set>\", !filter list or !filter clear".to_owned(),
+        };
+        self.send_quiet(&ctx, msg.channel_id, &reply).await?;
+        Ok(())
+    }
+
+    // !wish add <item>, !wish remove <item> and !wish list. Works in DMs too.
+    async fn wish_command(&self, ctx: &Context, msg: &Message) -> JWResult<()> {
+        let db = {
+            let lock = ctx.data.read().await;
+            Arc::clone(lock.get::<DBManager>().unwrap())
+        };
+
+        let user_id = msg.author.id.0 as i64;
+        let mut args = msg.content[6..].splitn(2, ' ');
+        let command = args.next().unwrap_or("");
+        let item = args.next().unwrap_or("").trim().trim_matches('"');
+        let reply = match (command, item) {
+            ("list", _) => {
+                let wishes = db.get_wishes(user_id).await?;
+                match wishes.len() {
+                    0 => "Your wishlist is empty. Add to it with !wish add <item>.".to_owned(),
+                    _ => format!("Your wishlist: {}", wishes.join(", ")),
+                }
+            },
+            ("add", i) if !i.is_empty() => {
+                match db.get_wishes(user_id).await?.len() >= WISHLIST_MAX {
+                    true => format!("Your wishlist is full, it can hold {} items.", WISHLIST_MAX),
+                    false => {
+                        db.add_wish(user_id, i).await?;
+                        format!("I'll DM you when {} is in the shop.", i)
+                    },
+                }
+            },
+            ("remove", i) if !i.is_empty() => match db.remove_wish(user_id, i).await? {
+                true => format!("Removed {} from your wishlist.", i),
+                false => format!("{} isn't on your wishlist.", i),
+            },
+            _ => "Usage: !wish add <item>, !wish remove <item> or !wish list".to_owned(),
+        };
+        self.send_quiet(&ctx, msg.channel_id, &reply).await?;
+        Ok(())
+    }
+
+    // !item <name> looks the item up on the JW server.
+    async fn item_lookup(&self, ctx: &Context, msg: &Message) -> JWResult<()> {
+        let manager = {
+            let lock = ctx.data.read().await;
+            Arc::clone(lock.get::<client::ClientManager>().unwrap())
+        };
+
+        let name = msg.content[6..].trim().trim_matches('"');
+        if name.is_empty() {
+            self.send_message(&ctx, msg.channel_id, "Usage: !item <name>").await?;
+            return Ok(());
+        }
+
+        let req = client::send_message(&manager, "item_lookup", name.to_owned()).await?;
+        let lookup: client::ItemLookup = serde_json::from_value(req.get_data().clone())?;
+        let item = match lookup.item {
+            Some(i) => i,
+            None => {
+                let reply = match lookup.suggestions.len() {
+                    0 => format!("I couldn't find an item called {}.", name),
+                    _ => {
+                        let suggestions: Vec<&str> = lookup.suggestions.iter().take(ITEM_SUGGESTIONS).map(|s| s.as_str()).collect();
+                        format!("I couldn't find an item called {}. Did you mean: {}?", name, suggestions.join(", "))
+                    },
+                };
+                self.send_quiet(&ctx, msg.channel_id, &reply).await?;
+                return Ok(());
+            },
+        };
+
+        msg.channel_id.send_message(&ctx.http, |m| m.embed(|e| {
+            e.title(&item.name);
+            e.field("Rarity", item.rarity.as_deref().unwrap_or("Unknown"), true);
+            e.field("Price", item.price.map_or("Unknown".to_owned(), |p| format!("{} V-Bucks", p)), true);
+            e.field("Last Seen", item.last_seen.as_deref().unwrap_or("Never"), true);
+            if let Some(image) = &item.image {
+                e.image(image);
+            }
+            e
+        })).await?;
+
+        Ok(())
+    }
+
     async fn unsubscribe_channel(&self, ctx: &Context, msg: &Message) -> JWResult<()> {
         let db = {
             let lock = ctx.data.read().await;
@@ -96,8 +381,41 @@ impl Handler {
             return Ok(());
         }
 
-        db.delete_channel(msg.channel_id.0 as i64).await?;
-        self.send_message(&ctx, msg.channel_id, "I'll stop sending messages here.").await?;
+        let mentioned = msg.content[12..].split_whitespace().filter_map(parse_channel_mention).next();
+        if let Some(channel) = mentioned {
+            self.get_channel_kind(&ctx, &msg, channel).await?;
+        }
+        let channel = mentioned.unwrap_or(msg.channel_id);
+        let place = match mentioned {
+            Some(c) => format!("in <#{}>", c.0),
+            None => "here".to_owned(),
+        };
+
+        // !unsubscribe ping keeps the subscription, but stops pinging its role.
+        if msg.content[12..].split_whitespace().any(|w| w.eq_ignore_ascii_case(UNSUBSCRIBE_PING)) {
+            db.set_channel_role(channel.0 as i64, None).await?;
+            self.send_message(&ctx, msg.channel_id, &format!("I'll stop pinging anyone {}.", place)).await?;
+            return Ok(());
+        }
+
+        let topic = match parse_topic(&msg.content[12..]) {
+            Ok(t) => t,
+            Err(reply) => {
+                self.send_quiet(&ctx, msg.channel_id, &reply).await?;
+                return Ok(());
+            },
+        };
+
+        match topic {
+            Some(t) if t != TOPIC_ALL => {
+                db.remove_channel_topic(channel.0 as i64, t).await?;
+                self.send_message(&ctx, msg.channel_id, &format!("I'll stop sending {} {}.", t, place)).await?;
+            },
+            _ => {
+                db.delete_channel(channel.0 as i64).await?;
+                self.send_message(&ctx, msg.channel_id, &format!("I'll stop sending messages {}.", place)).await?;
+            },
+        };
         Ok(())
     }
 
@@ -118,6 +436,124 @@ impl Handler {
 
         Ok(())
     }
+
+    // !broadcast at <RFC3339 time> <message>
+    async fn schedule_broadcast(&self, ctx: &Context, msg: &Message) -> JWResult<()> {
+        let db = {
+            let lock = ctx.data.read().await;
+            Arc::clone(lock.get::<DBManager>().unwrap())
+        };
+
+        let mut args = msg.content.splitn(4, ' ').skip(2);
+        let run_at = args.next().and_then(|v| DateTime::parse_from_rfc3339(v).ok());
+        let (run_at, content) = match (run_at, args.next()) {
+            (Some(t), Some(c)) => (t.with_timezone(&Utc), c),
+            _ => {
+                self.send_message(&ctx, msg.channel_id, "Usage: !broadcast at <RFC3339 time> <message>").await?;
+                return Ok(());
+            },
+        };
+        if run_at <= Utc::now() {
+            self.send_message(&ctx, msg.channel_id, "That time has already passed.").await?;
+            return Ok(());
+        }
+
+        let payload = broadcast::message_payload(content);
+        let id = db.create_scheduled_broadcast(&payload, run_at.timestamp(), msg.author.id.0 as i64).await?;
+        self.send_message(&ctx, msg.channel_id, &format!("Scheduled broadcast {} for {}", id, run_at.to_rfc3339())).await?;
+
+        Ok(())
+    }
+
+    async fn list_scheduled(&self, ctx: &Context, msg: &Message) -> JWResult<()> {
+        let db = {
+            let lock = ctx.data.read().await;
+            Arc::clone(lock.get::<DBManager>().unwrap())
+        };
+
+        let scheduled = db.get_scheduled_broadcasts().await?;
+        let mut reply = match scheduled.len() {
+            0 => "Nothing scheduled".to_owned(),
+            _ => "Scheduled broadcasts:".to_owned(),
+        };
+        for (id, payload, run_at) in scheduled {
+            let line = format!("\n{}: {} {}", id, Utc.timestamp(run_at, 0).to_rfc3339(), payload);
+            if reply.len() + line.len() > 2000 {
+                break;
+            }
+            reply += &line;
+        }
+        self.send_message(&ctx, msg.channel_id, &reply).await?;
+
+        Ok(())
+    }
+
+    // !broadcast <message>, or one of its subcommands. A message starting with a subcommand's name is always
+    // taken as that subcommand, so a mistyped one gets a usage reply instead of going out to every channel.
+    async fn broadcast_command(&self, ctx: &Context, msg: &Message) -> JWResult<()> {
+        let mut args = msg.content[10..].trim_start().splitn(2, ' ');
+        let subcommand = args.next().unwrap_or("");
+        let rest = args.next().unwrap_or("").trim();
+        match (subcommand, rest) {
+            ("status", "") => {
+                let status = {
+                    let data_lock = ctx.data.read().await;
+                    data_lock.get::<Coordinator>().unwrap().status()
+                };
+                self.send_message(&ctx, msg.channel_id, &status).await?;
+            },
+            ("at", _) => self.schedule_broadcast(&ctx, &msg).await?,
+            ("scheduled", "") => self.list_scheduled(&ctx, &msg).await?,
+            ("unschedule", id) => self.unschedule_broadcast(&ctx, &msg, id).await?,
+            ("cancel", id) => {
+                let cancelled = match id.parse::<i32>() {
+                    Ok(id) => {
+                        let data_lock = ctx.data.read().await;
+                        Some((id, data_lock.get::<Coordinator>().unwrap().cancel(id)))
+                    },
+                    Err(_) => None,
+                };
+                let reply = match cancelled {
+                    Some((id, true)) => format!("Cancelling Broadcast {}", id),
+                    Some((id, false)) => format!("Broadcast {} isn't running or queued", id),
+                    None => "Usage: !broadcast cancel <job id>".to_owned(),
+                };
+                self.send_message(&ctx, msg.channel_id, &reply).await?;
+            },
+            ("", _) | ("status", _) | ("scheduled", _) => {
+                self.send_message(&ctx, msg.channel_id, BROADCAST_USAGE).await?;
+            },
+            _ => {
+                let payload = broadcast::message_payload(&msg.content[11..]);
+                broadcast_message(Arc::clone(&ctx.data), BroadcastRequest {
+                    topic: db::TOPIC_ANNOUNCEMENTS,
+                    payload,
+                    report_to: Some((Arc::clone(&ctx.http), msg.author.id)),
+                    ..Default::default()
+                });
+            },
+        };
+
+        Ok(())
+    }
+
+    async fn unschedule_broadcast(&self, ctx: &Context, msg: &Message, id: &str) -> JWResult<()> {
+        let db = {
+            let lock = ctx.data.read().await;
+            Arc::clone(lock.get::<DBManager>().unwrap())
+        };
+
+        let reply = match id.parse::<i32>() {
+            Ok(id) => match db.delete_scheduled_broadcast(id).await? {
+                true => format!("Unscheduled broadcast {}", id),
+                false => format!("No scheduled broadcast {}", id),
+            },
+            Err(_) => "Usage: !broadcast unschedule <id>".to_owned(),
+        };
+        self.send_message(&ctx, msg.channel_id, &reply).await?;
+
+        Ok(())
+    }
 }
 
 #[async_trait]
@@ -137,7 +573,42 @@ impl EventHandler for Handler {
             }
         }
 
-        if msg.content == "!subscribe" {
+        if msg.content.starts_with("!wish ") {
+            if let Err(e) = self.wish_command(&ctx, &msg).await {
+                println!("Error: {}", e);
+            }
+            return;
+        }
+
+        if msg.content.starts_with("!item ") {
+            if let Err(e) = self.item_lookup(&ctx, &msg).await {
+                println!("Error: {}", e);
+            }
+            return;
+        }
+
+        if msg.content.starts_with("!filter ") {
+            if let Err(e) = self.filter_channel(&ctx, &msg).await {
+                println!("Error: {}", e);
+            }
+            return;
+        }
+
+        if msg.content.starts_with("!subscribe webhook ") {
+            if let Err(e) = self.subscribe_webhook(&ctx, &msg).await {
+                println!("Error: {}", e);
+            }
+            return;
+        }
+
+        if msg.content.starts_with("!unsubscribe webhook ") {
+            if let Err(e) = self.unsubscribe_webhook(&ctx, &msg).await {
+                println!("Error: {}", e);
+            }
+            return;
+        }
+
+        if msg.content == "!subscribe" || msg.content.starts_with("!subscribe ") {
             match self.subscribe_channel(&ctx, &msg).await {
                 Ok(_) => return,
                 Err(e) => {
@@ -147,7 +618,7 @@ impl EventHandler for Handler {
             };
         }
 
-        if msg.content == "!unsubscribe" {
+        if msg.content == "!unsubscribe" || msg.content.starts_with("!unsubscribe ") {
             match self.unsubscribe_channel(&ctx, &msg).await {
                 Ok(_) => return,
                 Err(e) => {
@@ -168,32 +639,365 @@ impl EventHandler for Handler {
                 return;
             }
 
+            if msg.content.starts_with("!retract ") || msg.content.starts_with("!edit ") {
+                // !retract <job id> deletes a broadcast, !edit <job id> <message> replaces it.
+                let mut args = msg.content.splitn(3, ' ').skip(1);
+                let job_id = args.next().and_then(|v| v.parse::<i32>().ok());
+                let edit = args.next().map(broadcast::edit_payload);
+                match (job_id, msg.content.starts_with("!edit "), edit) {
+                    (Some(id), false, _) => retract_broadcast(ctx.data, id, None, Some((ctx.http, msg.author.id))),
+                    (Some(id), true, Some(payload)) => retract_broadcast(ctx.data, id, Some(payload), Some((ctx.http, msg.author.id))),
+                    _ => {
+                        if let Err(e) = self.send_message(&ctx, msg.channel_id, "Usage: !retract <job id> or !edit <job id> <message>").await {
+                            println!("Error: {}", e);
+                        }
+                    },
+                };
+                return;
+            }
 
-            if msg.content.len() >= 10 && &msg.content[..10] == "!broadcast" {
-                broadcast_message(ctx.data, msg.content[11..].to_owned());
+            if msg.content.starts_with("!preview ") {
+                let payload = broadcast::message_payload(&msg.content[9..]);
+                preview_broadcast(ctx.data, payload, ctx.http, msg.channel_id);
+                return;
+            }
+
+            if msg.content == "!broadcast" || msg.content.starts_with("!broadcast ") {
+                if let Err(e) = self.broadcast_command(&ctx, &msg).await {
+                    println!("Error: {}", e);
+                }
             }
         }
     }
 }
 
-fn broadcast_message(context: Arc<RwLock<TypeMap>>, message: String) {
+const TOPIC_ALL: &str = "all";
+const UNSUBSCRIBE_PING: &str = "ping";
+const BROADCAST_USAGE: &str = "Usage: !broadcast <message>, or !broadcast status, at, scheduled, unschedule or cancel";
+const WISHLIST_MAX: usize = 25;
+const ITEM_SUGGESTIONS: usize = 5;
+const SUBSCRIBE_FAILED: &str = "I was not able to subscribe to that channel. I may not have permissions to do so.";
+
+// Finds the topic in a command's arguments, skipping over mentions. Unknown topics come back as a reply for the user.
+fn parse_topic(args: &str) -> Result<Option<&'static str>, String> {
+    let word = match args.split_whitespace().find(|w| !w.starts_with('<')) {
+        Some(w) => w.to_lowercase(),
+        None => return Ok(None),
+    };
+    if word == TOPIC_ALL {
+        return Ok(Some(TOPIC_ALL));
+    }
+    match db::TOPICS.iter().find(|t| **t == word) {
+        Some(t) => Ok(Some(*t)),
+        None => Err(format!("I don't know about {}. Topics are: {}, or all.", word, db::TOPICS.join(", "))),
+    }
+}
+
+// Picks the channel out of a <#id> mention.
+fn parse_channel_mention(text: &str) -> Option<ChannelId> {
+    if !text.starts_with("<#") || !text.ends_with('>') {
+        return None;
+    }
+    text[2..text.len() - 1].parse::<u64>().ok().map(ChannelId)
+}
+
+// Everything but the topic and payload is optional.
+#[derive(Default)]
+struct BroadcastRequest {
+    topic: &'static str,
+    payload: String,
+    attachment_url: Option<String>,
+    // Broadcasts with a key are skipped if the same key was broadcast within the dedup window.
+    key: Option<String>,
+    // Shop items are checked against channel filters. Without any, every channel gets the broadcast.
+    items: Vec<broadcast::ShopItem>,
+    // Finished broadcasts are always logged, and DMed to the owner if they started it.
+    report_to: Option<(Arc<Http>, UserId)>,
+    // The scheduled broadcast this is running, removed once the job is stored.
+    schedule: Option<i32>,
+}
+
+fn broadcast_message(context: Arc<RwLock<TypeMap>>, request: BroadcastRequest) {
+    let BroadcastRequest { topic, payload, attachment_url, key, items, report_to, schedule } = request;
     tokio::spawn(async move { 
-        let (token, http, db) = {
+        let (api, http, db, coordinator, window) = {
             let data_lock = context.read().await;
-            let token = data_lock.get::<BotToken>().unwrap().clone();
+            let api = data_lock.get::<DiscordApi>().unwrap();
             let http = data_lock.get::<HttpClient>().unwrap();
             let db = data_lock.get::<DBManager>().unwrap();
-            (token, Arc::clone(http), Arc::clone(db))
+            let coordinator = data_lock.get::<Coordinator>().unwrap();
+            let window = *data_lock.get::<DedupWindow>().unwrap();
+            (Arc::clone(api), Arc::clone(http), Arc::clone(db), Arc::clone(coordinator), window)
         };
-        let channels = match db.get_channels().await {
+        let mut channels = match db.get_channels(topic).await {
             Ok(r) => r,
             Err(e) => {
                 println!("DB Error: {:#?}", e);
                 return;
             },
         };
+        if !items.is_empty() {
+            let filters = match db.get_all_filters().await {
+                Ok(r) => r,
+                Err(e) => {
+                    println!("DB Error: {:#?}", e);
+                    return;
+                },
+            };
+            let count = channels.len();
+            channels.retain(|c| match filters.get(&c.discord) {
+                Some(f) => broadcast::shop_matches(&items, f),
+                None => true,
+            });
+            println!("Skipping {} channels whose filters don't match", count - channels.len());
+        }
+        // Fetched before the job is stored, so a broken image doesn't leave a job that can never resume.
+        let attachment = match &attachment_url {
+            Some(url) => match broadcast::fetch_attachment(&http, url).await {
+                Ok(a) => Some(a),
+                Err(e) => {
+                    println!("Attachment Error: {}", e);
+                    return;
+                },
+            },
+            None => None,
+        };
+        let channel_ids: Vec<i64> = channels.iter().map(|c| c.discord).collect();
+        let job_id = match db.create_broadcast_job(&payload, attachment_url.as_deref(), key.as_deref(), window as f64, &channel_ids, schedule).await {
+            Ok(Some(r)) => r,
+            Ok(None) => {
+                println!("Duplicate Broadcast {}: Already sent in the last {}s, skipping", key.unwrap_or_default(), window);
+                return;
+            },
+            Err(e) => {
+                println!("DB Error: {:#?}", e);
+                return;
+            },
+        };
+
+        let broadcast = broadcast::MessageBroadcast::new(db.clone(), job_id, channels, Arc::clone(&api), &payload, attachment);
+        let handle = coordinator.submit(broadcast);
+        if let Some((discord, user)) = &report_to {
+            send_owner(discord, *user, format!("Queued Broadcast {}, cancel with !broadcast cancel {}", job_id, job_id)).await;
+        }
+        // Only once the shop has been queued, so duplicate broadcasts don't DM anyone twice.
+        if !items.is_empty() {
+            send_wishlists(db, api, &coordinator, &items).await;
+        }
+        match handle.report().await {
+            Some(report) => send_report(report, report_to).await,
+            None => println!("Broadcast {} was dropped by the coordinator", job_id),
+        };
+    });
+}
+
+// DMs everyone with a wishlisted item in today's shop, queued behind the shop itself.
+async fn send_wishlists(db: Arc<db::DBManager>, api: Arc<broadcast::DiscordApi>, coordinator: &coordinator::BroadcastCoordinator, items: &[broadcast::ShopItem]) {
+    let names: Vec<String> = items.iter().map(|i| i.name.clone()).collect();
+    let matches = match db.get_wish_matches(&names).await {
+        Ok(r) => r,
+        Err(e) => {
+            println!("DB Error: {:#?}", e);
+            return;
+        },
+    };
+    if matches.is_empty() {
+        return;
+    }
+
+    // Matches come back ordered by user, so each user's items are next to each other.
+    let mut wishes: Vec<(i64, Vec<String>)> = Vec::new();
+    for (user, item) in matches {
+        match wishes.last_mut() {
+            Some((last, list)) if *last == user => list.push(item),
+            _ => wishes.push((user, vec![item])),
+        };
+    }
+    let messages = wishes.into_iter().map(|(user, list)| {
+        // Show the names the way the shop has them, not the lowercase ones from the wishlist.
+        let list: Vec<&str> = list.iter().map(|w| names.iter().find(|n| n.to_lowercase() == *w).map_or(w.as_str(), |n| n.as_str())).collect();
+        let content = format!("Good news! {} from your wishlist {} in the item shop today.", list.join(", "), match list.len() {
+            1 => "is",
+            _ => "are",
+        });
+        (user, broadcast::message_payload(&content))
+    }).collect();
 
-        broadcast::MessageBroadcast::new(db, channels, http, token, &message).await;
+    let handle = coordinator.submit(broadcast::MessageBroadcast::direct_messages(db, api, messages, "Wishlist DMs"));
+    tokio::spawn(async move {
+        if let Some(report) = handle.report().await {
+            println!("{}", report);
+        }
+    });
+}
+
+// Runs a broadcast without sending it anywhere but the owner's channel, then posts what would have happened.
+fn preview_broadcast(context: Arc<RwLock<TypeMap>>, payload: String, discord: Arc<Http>, channel: ChannelId) {
+    tokio::spawn(async move {
+        let (api, db) = {
+            let data_lock = context.read().await;
+            let api = data_lock.get::<DiscordApi>().unwrap();
+            let db = data_lock.get::<DBManager>().unwrap();
+            (Arc::clone(api), Arc::clone(db))
+        };
+        let channels = match db.get_channels(db::TOPIC_ANNOUNCEMENTS).await {
+            Ok(r) => r,
+            Err(e) => {
+                println!("DB Error: {:#?}", e);
+                return;
+            },
+        };
+
+        let count = channels.len();
+        let secs = api.estimate_duration(&channels).as_secs();
+        // Only the test channel is sent to, so it doesn't need to wait behind real broadcasts.
+        let broadcast = broadcast::MessageBroadcast::dry_run(db, channels, api, &payload, channel.0 as i64);
+        broadcast.await;
+        let mut summary = format!("Dry run to {} channels, estimated to take {}m {}s.\nPayload: {}", count, secs / 60, secs % 60, payload);
+        if summary.len() > 2000 {
+            summary.truncate(summary.char_indices().nth(1990).map(|(i, _)| i).unwrap_or(summary.len()));
+            summary.push_str("...");
+        }
+        if let Err(e) = channel.say(&discord, summary).await {
+            println!("Could not send broadcast preview: {}", e);
+        }
+    });
+}
+
+// Edits or deletes every message an earlier broadcast posted.
+fn retract_broadcast(context: Arc<RwLock<TypeMap>>, job_id: i32, edit: Option<String>, report_to: Option<(Arc<Http>, UserId)>) {
+    tokio::spawn(async move {
+        let (api, db, coordinator) = {
+            let data_lock = context.read().await;
+            let api = data_lock.get::<DiscordApi>().unwrap();
+            let db = data_lock.get::<DBManager>().unwrap();
+            let coordinator = data_lock.get::<Coordinator>().unwrap();
+            (Arc::clone(api), Arc::clone(db), Arc::clone(coordinator))
+        };
+        let messages = match db.get_delivered_messages(job_id).await {
+            Ok(r) => r,
+            Err(e) => {
+                println!("DB Error: {:#?}", e);
+                return;
+            },
+        };
+
+        let broadcast = broadcast::MessageBroadcast::retract(db, job_id, messages, api, edit);
+        let handle = coordinator.submit(broadcast);
+        if let Some((discord, user)) = &report_to {
+            send_owner(discord, *user, format!("Queued Retraction of {}, cancel with !broadcast cancel {}", job_id, handle.job_id())).await;
+        }
+        match handle.report().await {
+            Some(report) => send_report(report, report_to).await,
+            None => println!("Retraction of {} was dropped by the coordinator", job_id),
+        };
+    });
+}
+
+async fn send_report(report: broadcast::BroadcastReport, report_to: Option<(Arc<Http>, UserId)>) {
+    println!("{}", report);
+    if let Some((discord, user)) = report_to {
+        send_owner(&discord, user, report.to_string()).await;
+    }
+}
+
+async fn send_owner(discord: &Arc<Http>, user: UserId, message: String) {
+    let res = match user.create_dm_channel(discord).await {
+        Ok(dm) => dm.id.say(discord, message).await.map(|_| ()),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = res {
+        println!("Could not message owner: {}", e);
+    }
+}
+
+const SCHEDULE_INTERVAL: u64 = 30;
+
+// Fires scheduled broadcasts once they're due. Anything that came due while the bot was down goes out on startup.
+fn run_scheduler(context: Arc<RwLock<TypeMap>>, discord: Arc<Http>) {
+    tokio::spawn(async move {
+        let db = {
+            let data_lock = context.read().await;
+            Arc::clone(data_lock.get::<DBManager>().unwrap())
+        };
+
+        let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(SCHEDULE_INTERVAL));
+        loop {
+            interval.tick().await;
+            let due = match db.get_due_broadcasts().await {
+                Ok(r) => r,
+                Err(e) => {
+                    println!("DB Error: {:#?}", e);
+                    continue;
+                },
+            };
+            for (id, payload, author) in due {
+                println!("Running Scheduled Broadcast {}", id);
+                let key = db::DBManager::scheduled_key(id);
+                broadcast_message(Arc::clone(&context), BroadcastRequest {
+                    topic: db::TOPIC_ANNOUNCEMENTS,
+                    payload,
+                    key: Some(key),
+                    report_to: Some((Arc::clone(&discord), UserId(author as u64))),
+                    schedule: Some(id),
+                    ..Default::default()
+                });
+            }
+        }
+    });
+}
+
+// Picks up broadcasts that were cut short by a restart. Channels that already got the message are skipped.
+fn resume_broadcasts(context: Arc<RwLock<TypeMap>>) {
+    tokio::spawn(async move {
+        let (api, http, db, coordinator) = {
+            let data_lock = context.read().await;
+            let api = data_lock.get::<DiscordApi>().unwrap();
+            let http = data_lock.get::<HttpClient>().unwrap();
+            let db = data_lock.get::<DBManager>().unwrap();
+            let coordinator = data_lock.get::<Coordinator>().unwrap();
+            (Arc::clone(api), Arc::clone(http), Arc::clone(db), Arc::clone(coordinator))
+        };
+        let jobs = match db.get_unfinished_jobs().await {
+            Ok(r) => r,
+            Err(e) => {
+                println!("DB Error: {:#?}", e);
+                return;
+            },
+        };
+
+        // Everything is queued up front, so the status command shows the whole backlog.
+        let mut reports = Vec::with_capacity(jobs.len());
+        for (job_id, payload, attachment_url) in jobs {
+            let channels = match db.get_pending_deliveries(job_id).await {
+                Ok(r) => r,
+                Err(e) => {
+                    println!("DB Error: {:#?}", e);
+                    continue;
+                },
+            };
+            let attachment = match &attachment_url {
+                Some(url) => match broadcast::fetch_attachment(&http, url).await {
+                    Ok(a) => Some(a),
+                    Err(e) => {
+                        println!("Attachment Error: {}", e);
+                        continue;
+                    },
+                },
+                None => None,
+            };
+            println!("Resuming Broadcast {}: {} channels left", job_id, channels.len());
+            let broadcast = broadcast::MessageBroadcast::new(db.clone(), job_id, channels, Arc::clone(&api), &payload, attachment);
+            reports.push(coordinator.submit(broadcast));
+        }
+
+        for handle in reports {
+            let job_id = handle.job_id();
+            match handle.report().await {
+                Some(report) => println!("{}", report),
+                None => println!("Broadcast {} was dropped by the coordinator", job_id),
+            };
+        }
     });
 }
 
@@ -206,7 +1010,9 @@ async fn main() {
         .expect("Error creating client");
 
     let https = hyper_tls::HttpsConnector::new();
-    let http_client = hyper::Client::builder().build::<_, hyper::Body>(https);
+    let http_client = Arc::new(hyper::Client::builder().build::<_, hyper::Body>(https));
+    let api_url = env::var("DISCORD_API_URL").unwrap_or(broadcast::DEFAULT_API_URL.to_owned());
+    let dedup_window = env::var("BROADCAST_DEDUP_WINDOW").ok().and_then(|v| v.parse::<u64>().ok()).unwrap_or(DEFAULT_DEDUP_WINDOW);
     println!("Connecting to Database");
     let db_man = db::DBManager::new().await.unwrap();
 
@@ -217,26 +1023,61 @@ async fn main() {
         let mut lock = client_man.lock().unwrap();
         let client_data = Arc::clone(&client.data);
         lock.set_broadcast_hook("image", move |v| {
-            if let Some(val) = v.as_str() {
-                let msg = "https://wickshopbot.com/".to_owned() + val; 
-                broadcast_message(Arc::clone(&client_data), msg);
-            }
+            match broadcast::ShopImage::from_value(v) {
+                Some(shop) => broadcast_message(Arc::clone(&client_data), BroadcastRequest {
+                    topic: db::TOPIC_SHOP,
+                    payload: shop.payload(),
+                    attachment_url: shop.attachment_url(),
+                    key: Some(shop.idempotency_key()),
+                    items: shop.items.clone(),
+                    ..Default::default()
+                }),
+                None => println!("Invalid image broadcast: {}", v),
+            };
         });
     }
 
     {
         println!("Writing Context");
         let mut data = client.data.write().await;
-        data.insert::<BotToken>(token);
-        data.insert::<HttpClient>(Arc::new(http_client));
+        data.insert::<DiscordApi>(Arc::new(broadcast::DiscordApi::new(Arc::clone(&http_client), token, &api_url)));
+        data.insert::<HttpClient>(http_client);
         data.insert::<DBManager>(Arc::new(db_man));
         data.insert::<client::ClientManager>(client_man);
+        data.insert::<Coordinator>(Arc::new(coordinator::BroadcastCoordinator::start()));
+        data.insert::<DedupWindow>(dedup_window);
     }
 
+    resume_broadcasts(Arc::clone(&client.data));
+    run_scheduler(Arc::clone(&client.data), Arc::clone(&client.cache_and_http.http));
+
     shutdown::build_shutdown(&client.shard_manager);
 
     println!("Starting Bot");
     if let Err(why) = client.start_autosharded().await {
         println!("An error occurred while running the client: {:?}", why);
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_topics() {
+        assert_eq!(parse_topic(""), Ok(None));
+        assert_eq!(parse_topic("<@&123>"), Ok(None));
+        assert_eq!(parse_topic("shop"), Ok(Some(db::TOPIC_SHOP)));
+        assert_eq!(parse_topic("<@&123> Announcements"), Ok(Some(db::TOPIC_ANNOUNCEMENTS)));
+        assert_eq!(parse_topic("ALL"), Ok(Some(TOPIC_ALL)));
+        assert!(parse_topic("news").unwrap_err().contains("news"));
+    }
+
+    #[test]
+    fn parses_channel_mentions() {
+        assert_eq!(parse_channel_mention("<#123>"), Some(ChannelId(123)));
+        assert_eq!(parse_channel_mention("<@123>"), None);
+        assert_eq!(parse_channel_mention("<#abc>"), None);
+        assert_eq!(parse_channel_mention("<#123"), None);
+        assert_eq!(parse_channel_mention("123"), None);
+    }
+}
@@ -0,0 +1,193 @@
+use std::cmp;
+use std::collections::HashMap;
+use tokio::time::{Duration, Instant};
+
+const PRUNE_MIN: usize = 1024;
+
+// Discord buckets are shared between routes, but the limit is tracked per major parameter (the channel).
+// Routes are mapped to their bucket hash once a response tells us what it is.
+struct Bucket {
+    remaining: u32,
+    reset: Instant,
+}
+
+pub struct RateLimiter {
+    routes: HashMap<&'static str, String>,
+    buckets: HashMap<(String, i64), Bucket>,
+    prune_at: usize,
+    global_reset: Option<Instant>,
+    // Cloudflare bans the IP, so webhooks are held back as well.
+    ban_reset: Option<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self {
+            routes: HashMap::new(),
+            buckets: HashMap::new(),
+            prune_at: PRUNE_MIN,
+            global_reset: None,
+            ban_reset: None,
+        }
+    }
+
+    // When a global pause ends, if one is still running. A ban pauses everything too.
+    pub fn global_reset(&self) -> Option<Instant> {
+        match cmp::max(self.global_reset, self.ban_reset) {
+            Some(reset) if reset > Instant::now() => Some(reset),
+            _ => None,
+        }
+    }
+
+    // How long until a Cloudflare ban is over, if there is one.
+    pub fn ban_wait(&self) -> Option<Duration> {
+        let now = Instant::now();
+        match self.ban_reset {
+            Some(reset) if reset > now => Some(reset - now),
+            _ => None,
+        }
+    }
+
+    pub fn ban(&mut self, wait: Duration) {
+        let reset = Instant::now() + wait;
+        match self.ban_reset {
+            Some(current) if current >= reset => (),
+            _ => self.ban_reset = Some(reset),
+        };
+    }
+
+    // Pauses every request until the wait is over. An existing longer pause is kept.
+    pub fn pause_global(&mut self, wait: Duration) {
+        let reset = Instant::now() + wait;
+        match self.global_reset {
+            Some(current) if current >= reset => (),
+            _ => self.global_reset = Some(reset),
+        };
+    }
+
+    fn get_bucket(&self, route: &'static str, major: i64) -> Option<&Bucket> {
+        let hash = self.routes.get(route)?;
+        self.buckets.get(&(hash.clone(), major))
+    }
+
+    fn get_bucket_mut(&mut self, route: &'static str, major: i64) -> Option<&mut Bucket> {
+        let hash = self.routes.get(route)?.clone();
+        self.buckets.get_mut(&(hash, major))
+    }
+
+    // How long a request has to be held back before it can be sent, if at all.
+    pub fn wait_time(&self, route: &'static str, major: i64) -> Option<Duration> {
+        let bucket = self.get_bucket(route, major)?;
+        let now = Instant::now();
+        if bucket.remaining > 0 || bucket.reset <= now {
+            return None;
+        }
+        Some(bucket.reset - now)
+    }
+
+    // Take a slot from the bucket when a request goes out, so concurrent requests don't overdraw it
+    // before any of the responses come back.
+    pub fn acquire(&mut self, route: &'static str, major: i64) {
+        if let Some(bucket) = self.get_bucket_mut(route, major) {
+            if bucket.reset <= Instant::now() {
+                // Bucket has reset, the next response will tell us the new limit.
+                return;
+            }
+            bucket.remaining = bucket.remaining.saturating_sub(1);
+        }
+    }
+
+    pub fn update(&mut self, route: &'static str, major: i64, bucket: &Option<String>, remaining: u32, reset_after: u64) {
+        let hash = match bucket {
+            Some(h) => h,
+            None => return,
+        };
+        self.routes.insert(route, hash.clone());
+        self.buckets.insert((hash.clone(), major), Bucket {
+            remaining,
+            reset: Instant::now() + Duration::from_millis(reset_after),
+        });
+
+        // Drop buckets that have already reset, so long broadcasts don't keep one entry per channel forever.
+        if self.buckets.len() >= self.prune_at {
+            let now = Instant::now();
+            self.buckets.retain(|_, b| b.reset > now);
+            self.prune_at = cmp::max(PRUNE_MIN, self.buckets.len() * 2);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROUTE: &str = "POST /channels/{channel_id}/messages";
+
+    #[test]
+    fn waits_on_empty_buckets() {
+        let mut limiter = RateLimiter::new();
+        assert!(limiter.wait_time(ROUTE, 1).is_none());
+
+        let bucket = Some("abc".to_owned());
+        limiter.update(ROUTE, 1, &bucket, 0, 60 * 1000);
+        let wait = limiter.wait_time(ROUTE, 1).unwrap();
+        assert!(wait > Duration::from_secs(59) && wait <= Duration::from_secs(60));
+        // Buckets are per channel.
+        assert!(limiter.wait_time(ROUTE, 2).is_none());
+
+        limiter.update(ROUTE, 1, &bucket, 0, 0);
+        assert!(limiter.wait_time(ROUTE, 1).is_none());
+    }
+
+    #[test]
+    fn acquire_takes_from_the_bucket() {
+        let mut limiter = RateLimiter::new();
+        let bucket = Some("abc".to_owned());
+        limiter.update(ROUTE, 1, &bucket, 2, 60 * 1000);
+        limiter.acquire(ROUTE, 1);
+        assert!(limiter.wait_time(ROUTE, 1).is_none());
+        limiter.acquire(ROUTE, 1);
+        assert!(limiter.wait_time(ROUTE, 1).is_some());
+
+        // A bucket that's already reset is left for the next response to fill in.
+        limiter.update(ROUTE, 2, &bucket, 1, 0);
+        limiter.acquire(ROUTE, 2);
+        assert!(limiter.wait_time(ROUTE, 2).is_none());
+    }
+
+    #[test]
+    fn keeps_the_longest_pause() {
+        let mut limiter = RateLimiter::new();
+        assert!(limiter.global_reset().is_none());
+
+        limiter.pause_global(Duration::from_secs(60));
+        let reset = limiter.global_reset().unwrap();
+        limiter.pause_global(Duration::from_secs(1));
+        assert_eq!(limiter.global_reset(), Some(reset));
+        assert!(limiter.ban_wait().is_none());
+    }
+
+    #[test]
+    fn bans_pause_everything() {
+        let mut limiter = RateLimiter::new();
+        limiter.pause_global(Duration::from_secs(1));
+        limiter.ban(Duration::from_secs(60 * 60));
+        let wait = limiter.ban_wait().unwrap();
+        assert!(wait > Duration::from_secs(60 * 59));
+        assert!(limiter.global_reset().unwrap() > Instant::now() + Duration::from_secs(60 * 59));
+
+        limiter.ban(Duration::from_secs(1));
+        assert!(limiter.ban_wait().unwrap() > Duration::from_secs(60 * 59));
+    }
+
+    #[test]
+    fn prunes_reset_buckets() {
+        let mut limiter = RateLimiter::new();
+        let bucket = Some("abc".to_owned());
+        for channel in 0..PRUNE_MIN as i64 {
+            limiter.update(ROUTE, channel, &bucket, 0, 0);
+        }
+        assert!(limiter.buckets.len() < PRUNE_MIN);
+        assert_eq!(limiter.prune_at, PRUNE_MIN);
+    }
+}